@@ -0,0 +1,73 @@
+extern crate brainfuck;
+
+use std::env;
+use std::fs;
+use std::io::{self, Cursor};
+use std::time::{Duration, Instant};
+
+use brainfuck::{compile, optimize, CompileOptions, Config, Vm};
+
+// A program to measure, along with the input it is given
+struct Bench {
+    name: &'static str,
+    source: String,
+    input: Vec<u8>,
+}
+
+// Programs which spend their time computing, writing and reading
+fn benches() -> Vec<Bench> {
+    vec![
+        Bench {
+            name: "mandelbrot",
+            source: fs::read_to_string("examples/mandelbrot.brainfuck")
+                .expect("run the benchmarks from the root of the repository"),
+            input: vec![],
+        },
+        Bench {
+            // Write 'A' a million times, one cell at a time
+            name: "output",
+            source: "++++++++[>++++++++<-]>+>++++++++++[>++++++++++<-]>[>++++++++++\
+                     [>++++++++++[>++++++++++[>++++++++++[<<<<<<.>>>>>>-]<-]<-]<-]<-]"
+                .to_string(),
+            input: vec![],
+        },
+        Bench {
+            // Copy ten megabytes of input to the output
+            name: "input",
+            source: ",[.,]".to_string(),
+            input: (0..10_000_000).map(|i| (i % 255 + 1) as u8).collect(),
+        },
+    ]
+}
+
+// Compile and run a program once, returning how long the run took
+fn measure(bench: &Bench) -> Duration {
+    let program = compile(&bench.source, &CompileOptions::default()).expect("benchmarks compile");
+    let program = optimize(program);
+
+    let input = Box::new(Cursor::new(bench.input.clone()));
+    let mut vm: Vm<u8> = Vm::new(program, &Config::default(), input, Box::new(io::sink()));
+
+    let start = Instant::now();
+    vm.run_to_completion().expect("benchmarks run");
+    start.elapsed()
+}
+
+// Run every benchmark whose name was given, or all of them, and print the
+// fastest and the mean of several runs
+fn main() {
+    let names: Vec<String> = env::args().skip(1).collect();
+    let runs = 5;
+
+    for bench in benches() {
+        if !names.is_empty() && !names.iter().any(|name| name == bench.name) {
+            continue;
+        }
+
+        let times: Vec<Duration> = (0..runs).map(|_| measure(&bench)).collect();
+        let fastest = times.iter().min().copied().unwrap_or_default();
+        let mean = times.iter().sum::<Duration>() / runs;
+        println!("{:<12} fastest {:>10.3} ms   mean {:>10.3} ms", bench.name,
+                 fastest.as_secs_f64() * 1e3, mean.as_secs_f64() * 1e3);
+    }
+}
@@ -1,205 +1,1195 @@
-use std::fs;
+extern crate brainfuck;
+
 use std::env;
+use std::path::Path;
+use std::collections::VecDeque;
 use std::process;
-use std::io::{stdout, stdin, Read, Write};
+use std::thread;
+use std::fs::{self, File};
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::{self, stdout, stdin, stderr, Cursor, Read, Write};
 
-// Instructions for the VM
-enum Inst {
-    Inc(usize),
-    Dec(usize),
-    ShiftRight(usize),
-    ShiftLeft(usize),
-    Input(usize),
-    Output(usize),
-    LoopStart(usize),
-    LoopEnd(usize),
-}
+use brainfuck::{analyze, check_brackets, compile, compile_file, compile_source, decode, disassemble, emit_c, emit_rust, emit_wat};
+use brainfuck::{encode, optimize, unroll, validate};
+use brainfuck::{Bounds, Cell, Inst, Warning, WarningKind, CompileError, CompileOptions, CompileErrorKind, Config, Eof, Program, RunError, RunStats, Vm};
+use brainfuck::TAPE_LENGTH;
 
 // Print a message to standard error and exit
-fn error(message: &str) {
+fn error(message: &str) -> ! {
     eprintln!("{}", message);
     process::exit(1);
 }
 
-// If the last instruction in the "bytecode" is of the same type as
-// the one to be appended, then the amount of the last instruction is
-// increased instead
-macro_rules! sized_inst {
-    ($output: expr, $index: expr, $type: tt) => {{
-        if let Some($type(n)) = $output.last() {
-            $output[$index - 1] = $type(n + 1);
-            continue;
-        } else {
-            $output.push($type(1));
+// An endless stream of pseudo-random bytes from a xorshift generator, the
+// same for every run with the same seed
+struct RandomInput(u64);
+
+impl Read for RandomInput {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        for byte in buffer.iter_mut() {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            *byte = (self.0 >> 32) as u8;
         }
-    }};
+        Ok(buffer.len())
+    }
 }
 
-// Compile a BF program to an instruction chunk for the turing machine
-fn compile(file_path: &str) -> Vec<Inst> {
-    let source = fs::read_to_string(file_path).unwrap_or_else(|_| {
-        eprintln!("error: failed to read file '{}'", file_path);
-        process::exit(1);
-    });
+// An output which only keeps the last bytes written to it, writing them out
+// once it is dropped at the end of the program
+struct Tail<'a> {
+    kept: VecDeque<u8>,
+    size: usize,
+    output: Box<dyn Write + 'a>,
+}
 
-    let mut output = vec![];
-    let mut loops = vec![];
-    let mut index = 0;
-
-    let mut line = 1;
-    let mut column = 0;
-
-    use Inst::*;
-    for c in source.chars() {
-        column += 1;
-
-        match c {
-            '+' => sized_inst!(output, index, Inc),
-            '-' => sized_inst!(output, index, Dec),
-            '>' => sized_inst!(output, index, ShiftRight),
-            '<' => sized_inst!(output, index, ShiftLeft),
-            ',' => sized_inst!(output, index, Input),
-            '.' => sized_inst!(output, index, Output),
-            '[' => {
-                loops.push((index, line, column));
-                output.push(LoopStart(index));
-            },
-            ']' => match loops.pop() {
-                Some((0, _, _)) => {
-                    // Loop at the start of the program is a guaranted comment
-                    index = 0;
-                    loops.clear();
-                    output.clear();
-                    continue;
-                },
-                Some((i, _, _)) => {
-                    output[i] = LoopStart(index);
-                    output.push(LoopEnd(i));
-                },
-                None => error(&format!("{}:{}:{} Unbalanced ']'",
-                                       file_path, line, column))
-            },
-            '\n' => {
-                line += 1;
-                column = 0;
-                continue;
+impl<'a> Write for Tail<'a> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let recent = &bytes[bytes.len().saturating_sub(self.size)..];
+        let excess = (self.kept.len() + recent.len()).saturating_sub(self.size);
+        self.kept.drain(..excess);
+        self.kept.extend(recent);
+        Ok(bytes.len())
+    }
+
+    // Nothing is written until the end, so prompts are never shown
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Tail<'a> {
+    fn drop(&mut self) {
+        let output = &mut self.output;
+        output.write_all(self.kept.make_contiguous())
+            .and_then(|_| output.flush())
+            .unwrap_or_else(|_| error("error: failed to write the tail of the output"));
+    }
+}
+
+// The languages a program can be translated to instead of running it, or
+// just its size
+enum Emit {
+    C,
+    Rust,
+    Wat,
+    Bytecode,
+    Counts
+}
+
+// The options of the interpreter collected from the command line
+struct Options {
+    files: Vec<String>,
+    compile: CompileOptions,
+    config: Config,
+    cell_size: usize,
+    signed: bool,
+    optimize: bool,
+    unroll: usize,
+    warn: bool,
+    dump: bool,
+    hist: bool,
+    stats: bool,
+    time: bool,
+    dump_on_interrupt: bool,
+    debug: bool,
+    break_on_output: Option<u8>,
+    step_delay: Option<Duration>,
+    repl: bool,
+    shared_tape: bool,
+    fork: bool,
+    concat: bool,
+    bang_input: bool,
+    input_eager: bool,
+    emit: Option<Emit>,
+    input_file: Option<String>,
+    random_input: Option<u64>,
+    output_file: Option<String>,
+    tail: Option<usize>,
+    cache_dir: Option<String>,
+    save_state: Option<String>,
+    load_state: Option<String>
+}
+
+const DEBUG_HELP: &str = "commands:
+  step             execute one instruction
+  continue         run until a breakpoint or the end of the program
+  break <ip>       set a breakpoint at an instruction index
+  watch <addr>     stop whenever the value of a cell changes
+  print <addr>     print the value of a cell
+  quit             stop the program";
+
+// Execute a single instruction, reporting every watched cell it changed.
+// Returns whether any of them did
+fn step_watched<T: Cell>(vm: &mut Vm<T>, watches: &mut [(usize, T)]) -> Result<bool, RunError> {
+    let ip = vm.ip();
+    vm.step()?;
+
+    let mut changed = false;
+    for watch in watches.iter_mut() {
+        let value = vm.cell(watch.0).unwrap_or(T::ZERO);
+        if value != watch.1 {
+            eprintln!("[{}] {} -> {} (ip = {})", watch.0, watch.1, value, ip);
+            watch.1 = value;
+            changed = true;
+        }
+    }
+
+    Ok(changed)
+}
+
+// Whether the next instruction writes the byte to break on
+fn breaks_on_output<T: Cell>(vm: &Vm<T>, byte: Option<u8>) -> bool {
+    match (vm.current(), byte) {
+        (Some(&Inst::Output(_)), Some(byte)) => vm.cell(vm.pointer()).map(Cell::low_byte) == Some(byte),
+        _ => false
+    }
+}
+
+// Run until a watched cell changes, a breakpoint is reached, the byte to
+// break on is about to be written or the program ends
+fn resume<T: Cell>(vm: &mut Vm<T>,
+                   breakpoints: &[usize],
+                   watches: &mut [(usize, T)],
+                   break_on_output: Option<u8>) -> Result<(), RunError> {
+    while !step_watched(vm, watches)? && vm.current().is_some() && !breakpoints.contains(&vm.ip()) {
+        if breaks_on_output(vm, break_on_output) {
+            eprintln!("about to output {:#04x}", break_on_output.unwrap_or(0));
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Run a program slowly enough to watch, pausing after every instruction.
+// The output is flushed before each pause so it appears as it is written
+fn run_slowly<T: Cell>(vm: &mut Vm<T>, delay: Duration) -> Result<(), RunError> {
+    while vm.step()? {
+        vm.flush()?;
+        thread::sleep(delay);
+    }
+
+    Ok(())
+}
+
+// Drive a virtual machine interactively from commands read on standard input.
+// The program reads its own input through a buffer, so when both come from
+// standard input the program's input should be typed when it asks for it.
+// With a byte to break on, the program runs until it is about to write it
+// before the first command is read
+fn debug<T: Cell>(vm: &mut Vm<T>, break_on_output: Option<u8>) -> Result<(), RunError> {
+    let mut breakpoints = vec![];
+    let mut watches = vec![];
+    let mut line = String::new();
+    let mut stopped = true;
+
+    if break_on_output.is_some() && !breaks_on_output(vm, break_on_output) {
+        resume(vm, &breakpoints, &mut watches, break_on_output)?;
+    }
+
+    while let Some(inst) = vm.current() {
+        if stopped {
+            eprintln!("ip = {} {:?} mp = {}", vm.ip(), inst, vm.pointer());
+            stopped = false;
+        }
+
+        vm.flush()?;
+        eprint!("(debug) ");
+        stderr().flush()?;
+
+        line.clear();
+        if stdin().read_line(&mut line)? == 0 {
+            break;
+        }
+
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next().map(|word| word.parse::<usize>())) {
+            (Some("step"), None) | (Some("s"), None) => {
+                step_watched(vm, &mut watches)?;
+                stopped = true;
+            },
+
+            (Some("continue"), None) | (Some("c"), None) => {
+                resume(vm, &breakpoints, &mut watches, break_on_output)?;
+                stopped = true;
+            },
+
+            (Some("break"), Some(Ok(ip))) | (Some("b"), Some(Ok(ip))) => {
+                breakpoints.push(ip);
+            },
+
+            (Some("watch"), Some(Ok(address))) | (Some("w"), Some(Ok(address))) => {
+                watches.push((address, vm.cell(address).unwrap_or(T::ZERO)));
+            },
+
+            (Some("print"), Some(Ok(address))) | (Some("p"), Some(Ok(address))) => {
+                match vm.cell(address) {
+                    Some(cell) => eprintln!("[{}] = {}", address, cell),
+                    None => eprintln!("error: address {} is outside of the tape", address)
+                }
             },
-            _ => continue
+
+            (Some("quit"), None) | (Some("q"), None) => process::exit(0),
+            (None, _) => {},
+            _ => eprintln!("{}", DEBUG_HELP)
         }
+    }
+
+    Ok(())
+}
 
-        index += 1;
+// The files a program was read from, each with the line of the whole
+// program where it starts
+struct Spans(Vec<(String, usize)>);
+
+impl Spans {
+    // The file holding a line of the whole program, and the line within it
+    fn locate(&self, line: usize) -> (&str, usize) {
+        let (ref name, start) = *self.0.iter()
+            .rev()
+            .find(|&&(_, start)| start <= line)
+            .unwrap_or(&self.0[0]);
+
+        (name, (line + 1).saturating_sub(start))
     }
+}
 
-    if let Some((_, line, column)) = loops.last() {
-        error(&format!("{}:{}:{}: Unterminated '['",
-                       file_path, line, column));
+// The 64-bit FNV-1a hash of the version, the compile options and the source,
+// which unlike the hasher of the standard library stays the same between
+// releases of Rust, so a cache survives updating the compiler
+fn cache_key(source: &str, options: &CompileOptions) -> u64 {
+    let options = format!("{:?}", options);
+    [VERSION, &options, source].iter()
+        .flat_map(|part| part.bytes().chain(Some(0)))
+        .fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// Load the program compiled from the same source with the same options out of
+// the cache directory. The file starts with the key and the length of the
+// source, and both have to match before the program in it is used
+fn load_cached(source: &str, options: &CompileOptions, dir: &str) -> Option<Program> {
+    let key = cache_key(source, options);
+    let bytes = fs::read(Path::new(dir).join(format!("{:016x}.bfc", key))).ok()?;
+    if bytes.len() < 16 ||
+        bytes[..8] != key.to_le_bytes() ||
+        bytes[8..16] != (source.len() as u64).to_le_bytes() {
+        return None;
     }
 
-    output
+    decode(&bytes[16..]).filter(|program| validate(&program.code).is_ok())
 }
 
-// The length of the tape
-const TAPE_LENGTH: usize = 30000;
+// Store a compiled program in the cache directory for the next run. A cache
+// which cannot be written only costs the time to compile the program again
+fn store_cached(source: &str, options: &CompileOptions, dir: &str, program: &Program) {
+    let key = cache_key(source, options);
+    let mut bytes = key.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&(source.len() as u64).to_le_bytes());
+    bytes.extend(encode(program));
 
-// The virtual machine where the program is executed
-struct Vm {
-    memory: [u8; TAPE_LENGTH],
-    mp: usize,
-    ip: usize,
-    program: Vec<Inst>
+    let path = Path::new(dir).join(format!("{:016x}.bfc", key));
+    fs::create_dir_all(dir).and_then(|_| fs::write(path, bytes)).ok();
 }
 
-// Change the value of a generic number with modulo-wrapping
-macro_rules! change {
-    ($number: expr, $amount: expr, $divisor: expr, $op: tt, $type: tt) => {{
-        let d = $divisor as isize;
-        let number = (($number as isize $op $amount as isize) % d + d) % d;
-        $number = number as $type;
-    }};
+// Compile a program from the concatenated sources of the files, reading
+// standard input for a path of '-'. A program read from standard input
+// consumes all of it, so its own input instructions always see the end of
+// input. With '--bang-input', everything after the first '!' is returned as
+// the input of the program instead
+fn load(files: &[String], options: &Options) -> Result<(Program, String, Spans), Vec<CompileError>> {
+    // A single file is compiled while it is read, so the whole source never
+    // has to fit in memory. The input after a '!' and the cache need all of
+    // it, and an unmatched bracket reads it again to report every other one
+    if let [ref file_path] = *files {
+        if file_path != "-" && !options.bang_input && options.cache_dir.is_none() {
+            let spans = Spans(vec![(file_path.to_string(), 1)]);
+            match compile_file(file_path, &options.compile) {
+                Ok(program) => return Ok((program, String::new(), spans)),
+                Err(ref err) if matches!(err.kind, CompileErrorKind::UnbalancedClose |
+                                                   CompileErrorKind::UnterminatedOpen) => {},
+                Err(err) => return Err(vec![err])
+            }
+        }
+    }
+
+    let mut source = String::new();
+    let mut spans = vec![];
+
+    for file_path in files {
+        let read_failed = |_| vec![CompileError {
+            path: Some(file_path.to_string()),
+            line: 0,
+            column: 0,
+            kind: CompileErrorKind::ReadFailed,
+            context: String::new()
+        }];
+
+        let (text, name) = if file_path == "-" {
+            let mut text = String::new();
+            stdin().read_to_string(&mut text).map_err(read_failed)?;
+            (text, "<stdin>")
+        } else {
+            (fs::read_to_string(file_path).map_err(read_failed)?, file_path.as_str())
+        };
+
+        // Every file starts on a line of its own
+        if !source.is_empty() && !source.ends_with('\n') {
+            source.push('\n');
+        }
+
+        spans.push((name.to_string(), source.matches('\n').count() + 1));
+        source += &text;
+    }
+
+    let spans = Spans(spans);
+    let (code, input) = match source.find('!') {
+        Some(index) if options.bang_input => (&source[..index], &source[index + 1..]),
+        _ => (&source[..], "")
+    };
+
+    // The excerpt of the source is numbered by the lines of the whole
+    // program, so it is only kept when there is a single file
+    let locate = |err: CompileError| {
+        let (path, line) = spans.locate(err.line);
+        CompileError {
+            path: Some(path.to_string()),
+            line,
+            context: if files.len() == 1 { err.context } else { String::new() },
+            ..err
+        }
+    };
+
+    // A cached program was checked when it was compiled
+    let cached = options.cache_dir.as_ref()
+        .and_then(|dir| load_cached(code, &options.compile, dir));
+    if let Some(program) = cached {
+        return Ok((program, input.to_string(), spans));
+    }
+
+    // Every unmatched bracket is reported at once, before compiling
+    check_brackets(code, &options.compile)
+        .map_err(|errors| errors.into_iter().map(locate).collect::<Vec<_>>())?;
+
+    let program = compile(code, &options.compile).map_err(|err| vec![locate(err)])?;
+    if let Some(ref dir) = options.cache_dir {
+        store_cached(code, &options.compile, dir, &program);
+    }
+
+    Ok((program, input.to_string(), spans))
 }
 
-// The custom turing machine for the generated instructions
-impl Vm {
+// Read programs line by line from standard input and run each on the same
+// virtual machine, so the tape and the pointer survive between lines. Lines
+// are collected until every loop in them is closed. Standard input is taken
+// by the lines themselves, so the programs read from the input file if any
+fn repl<T: Cell>(options: &Options) {
+    let input: Box<dyn Read> = match options.input_file {
+        Some(ref path) => Box::new(File::open(path).unwrap_or_else(|_| {
+            error(&format!("error: failed to open input file '{}'", path))
+        })),
+        None => Box::new(io::empty())
+    };
 
-    // Create a virtual machine from a source program
-    fn new(program: Vec<Inst>) -> Self {
-        Self {
-            memory: [0; TAPE_LENGTH],
-            mp: 0,
-            ip: 0,
-            program
+    let mut vm: Vm<T> = Vm::new(Program::default(), &options.config, input, Box::new(stdout()));
+    let mut source = String::new();
+    let mut line = String::new();
+
+    loop {
+        eprint!("{}", if source.is_empty() { "bf> " } else { "... " });
+        stderr().flush().unwrap_or_else(|_| error("error: failed to write the prompt"));
+
+        line.clear();
+        match stdin().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => source += &line,
+            Err(_) => error("error: failed to read from standard input")
+        }
+
+        let program = match compile_source(&source, "<repl>", &options.compile) {
+            Ok(program) => program,
+            Err(ref err) if err.kind == CompileErrorKind::UnterminatedOpen => continue,
+            Err(err) => {
+                eprintln!("{}", err);
+                source.clear();
+                continue;
+            }
+        };
+        source.clear();
+
+        vm.load(if options.optimize { optimize(program) } else { program });
+        if let Err(err) = vm.start() {
+            eprintln!("error: {}", err);
         }
     }
+}
 
-    // Execute the current instruction
-    fn execute(&mut self) {
-        use Inst::*;
+// Set once SIGINT arrives, when asked to dump the state on an interrupt
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-        match self.program[self.ip] {
-            Inc(amount) => change!(self.memory[self.mp], amount, u8::MAX, +, u8),
-            Dec(amount) => change!(self.memory[self.mp], amount, u8::MAX, -, u8),
+extern "C" fn on_interrupt(_: i32) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+// Stop the program instead of the whole process on SIGINT, through the
+// C library which the standard library already links against
+#[cfg(unix)]
+fn catch_interrupt() {
+    const SIGINT: i32 = 2;
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+
+    unsafe {
+        signal(SIGINT, on_interrupt);
+    }
+}
 
-            ShiftRight(amount) => change!(self.mp, amount, TAPE_LENGTH, +, usize),
-            ShiftLeft(amount) => change!(self.mp, amount, TAPE_LENGTH, -, usize),
+#[cfg(not(unix))]
+fn catch_interrupt() {
+    error("error: --dump-on-interrupt is only supported on unix");
+}
+
+// Format a duration in the largest unit which keeps it above one
+fn human_duration(duration: Duration) -> String {
+    let micros = duration.as_secs_f64() * 1e6;
+    if micros < 1e3 {
+        format!("{:.0} µs", micros)
+    } else if micros < 1e6 {
+        format!("{:.3} ms", micros / 1e3)
+    } else {
+        format!("{:.3} s", micros / 1e6)
+    }
+}
+
+// Write the number of instructions and loops in a program, along with how
+// deeply its loops are nested
+fn write_counts(program: &[Inst], out: &mut impl Write) -> io::Result<()> {
+    let mut loops = 0;
+    let mut depth = 0;
+    let mut deepest = 0;
+
+    for inst in program {
+        match *inst {
+            Inst::LoopStart(_) => {
+                loops += 1;
+                depth += 1;
+                deepest = deepest.max(depth);
+            },
+            Inst::LoopEnd(_) => depth -= 1,
+            _ => {}
+        }
+    }
+
+    writeln!(out, "instructions: {}", program.len())?;
+    writeln!(out, "loops: {}", loops)?;
+    writeln!(out, "max nesting: {}", deepest)
+}
+
+// Compare the programs which finished in a table, one line each
+fn print_report(report: &[(String, RunStats, Duration)]) {
+    let width = report.iter().map(|row| row.0.chars().count()).max().unwrap_or(0).max(4);
+    eprintln!("{:<4$}  {:>14}  {:>12}  {:>10}", "file", "instructions", "output bytes", "time", width);
+    for &(ref file_path, stats, elapsed) in report {
+        eprintln!("{:<4$}  {:>14}  {:>12}  {:>10}", file_path, stats.instructions, stats.written,
+                  human_duration(elapsed), width);
+    }
+}
 
-            Output(amount) => {
-                for _ in 0..amount {
-                    print!("{}", self.memory[self.mp] as char);
+// Run each file in sequence on a fresh virtual machine, or on the same one
+// when the tape is shared between them. A file which fails to compile or
+// run is reported and skipped, and the result is whether every file passed
+fn run<T: Cell>(options: &Options) -> bool {
+    if options.repl {
+        repl::<T>(options);
+        return true;
+    }
+
+    let config = &options.config;
+
+    // Every program writes to the same output file, one after another
+    let output_file = options.output_file.as_ref().map(|path| {
+        File::create(path).unwrap_or_else(|_| {
+            error(&format!("error: failed to create output file '{}'", path))
+        })
+    });
+
+    // The virtual machine of the last program when the tape is shared
+    let mut shared: Option<Vm<T>> = None;
+    let mut failed = 0;
+
+    // What every program which finished did, compared once they all ran
+    let mut report = vec![];
+
+    // Concatenated files make up a single program
+    let programs: Vec<&[String]> = if options.concat {
+        vec![&options.files]
+    } else {
+        options.files.chunks(1).collect()
+    };
+
+    for files in &programs {
+        let file_path = &files.join(" ");
+        let (program, embedded, spans) = match load(files, options) {
+            Ok(loaded) => loaded,
+            Err(errors) => {
+                for err in errors {
+                    eprintln!("{}", err);
                 }
-                stdout().flush().unwrap_or_else(|_| error("error: failed to flush stdout"));
-            },
-
-            Input(amount) => {
-                for _ in 0..amount {
-                    self.memory[self.mp] = stdin()
-                        .bytes()
-                        .next()
-                        .unwrap_or_else(|| {
-                            eprintln!("error: failed to read stdin");
-                            process::exit(1);
-                        })
-                        .unwrap_or_else(|_| {
-                            eprintln!("error: failed to read stdin");
-                            process::exit(1);
-                        })
+                failed += 1;
+                continue;
+            }
+        };
+
+        // A program only starts on a blank tape unless it runs on what an
+        // earlier one left, or on a saved state
+        let blank = !(options.shared_tape || options.concat || options.load_state.is_some());
+        if options.warn {
+            for warning in analyze(&program, config.tape_size, blank) {
+                let (path, line) = spans.locate(warning.line);
+                let kind = match warning.kind {
+                    WarningKind::Unreachable { end: (end, column) } =>
+                        WarningKind::Unreachable { end: (spans.locate(end).1, column) },
+                    kind => kind
+                };
+                eprintln!("{}:{}", path, Warning { line, kind, ..warning });
+            }
+        }
+
+        let program = if options.optimize { optimize(program) } else { program };
+        let program = if options.unroll > 0 { unroll(program, options.unroll) } else { program };
+        if let Err(err) = validate(&program.code) {
+            eprintln!("{}", CompileError { path: Some(file_path.to_string()), ..err });
+            failed += 1;
+            continue;
+        }
+
+        if let Some(ref emit) = options.emit {
+            match *emit {
+                Emit::C => emit_c(&program.code, config.tape_size, &mut stdout()),
+                Emit::Rust => emit_rust(&program.code, config.tape_size, &mut stdout()),
+                Emit::Wat => emit_wat(&program.code, config.tape_size, &mut stdout()),
+                Emit::Bytecode => write!(stdout(), "{}", disassemble(&program.code)),
+                Emit::Counts => write_counts(&program.code, &mut stdout())
+            }.unwrap_or_else(|_| error("error: failed to write the translated program"));
+            continue;
+        }
+
+        let mut vm = match shared.take() {
+            Some(mut vm) => {
+                // The input embedded in this program comes before any input
+                // the ones before it left unread
+                vm.load(program);
+                if !embedded.is_empty() {
+                    vm.prepend_input(Cursor::new(embedded.into_bytes()));
                 }
+                vm
             },
 
-            LoopStart(i) => {
-                if self.memory[self.mp] == 0 {
-                    self.ip = i;
+            None => {
+                // Input embedded in the program is read before anything else,
+                // and all of it is read upfront when asked to
+                let mut input: Box<dyn Read> = match (options.random_input, &options.input_file) {
+                    (Some(seed), _) => Box::new(RandomInput(seed)),
+                    (None, Some(path)) => Box::new(File::open(path).unwrap_or_else(|_| {
+                        error(&format!("error: failed to open input file '{}'", path))
+                    })),
+                    (None, None) => Box::new(stdin())
+                };
+                let input: Box<dyn Read> = if options.input_eager {
+                    let mut bytes = embedded.into_bytes();
+                    input.read_to_end(&mut bytes)
+                        .unwrap_or_else(|_| error("error: failed to read the input"));
+                    Box::new(Cursor::new(bytes))
+                } else {
+                    Box::new(Cursor::new(embedded.into_bytes()).chain(input))
+                };
+
+                let output: Box<dyn Write> = match output_file {
+                    Some(ref file) => Box::new(file),
+                    None => Box::new(stdout())
+                };
+                let output: Box<dyn Write> = match options.tail {
+                    Some(size) => Box::new(Tail { kept: VecDeque::new(), size, output }),
+                    None => output
+                };
+
+                let mut vm = Vm::new(program, config, input, output);
+                if let Some(ref path) = options.load_state {
+                    File::open(path).and_then(|mut file| vm.load_state(&mut file)).unwrap_or_else(|err| {
+                        error(&format!("error: failed to load the state from '{}': {}", path, err))
+                    });
+                }
+                vm
+            }
+        };
+
+        if options.dump_on_interrupt {
+            vm.interrupt_on(&INTERRUPTED);
+        }
+
+        let start = Instant::now();
+        let result = if options.debug {
+            debug(&mut vm, options.break_on_output).map(|_| vm.stats())
+        } else if let Some(delay) = options.step_delay {
+            run_slowly(&mut vm, delay).map(|_| vm.stats())
+        } else {
+            vm.run_to_completion()
+        };
+        let elapsed = start.elapsed();
+
+        // The state is saved however the run ended, so an interrupted or
+        // limited run can be resumed
+        if let Some(ref path) = options.save_state {
+            File::create(path).and_then(|mut file| vm.save_state(&mut file)).unwrap_or_else(|_| {
+                error(&format!("error: failed to save the state to '{}'", path))
+            });
+        }
+
+        let stats = match result {
+            Ok(stats) => stats,
+            Err(err) => {
+                match err {
+                    RunError::OutOfBounds { ip, line, column } => {
+                        let (path, line) = spans.locate(line);
+                        eprintln!("{}:{}", path, RunError::OutOfBounds { ip, line, column });
+                    },
+                    RunError::Interrupted { .. } => {
+                        vm.flush().ok();
+                        eprintln!("{}: {}", file_path, err);
+                        eprintln!("cells:{}", vm.nearby());
+                        drop(vm);
+                        process::exit(130);
+                    },
+                    _ => eprintln!("{}: error: {}", file_path, err)
                 }
-            },
 
-            LoopEnd(i) => {
-                if self.memory[self.mp] != 0 {
-                    self.ip = i;
+                failed += 1;
+                if options.shared_tape {
+                    shared = Some(vm);
                 }
+                continue;
             }
+        };
+
+        if options.time {
+            eprintln!("{}: {}", file_path, human_duration(elapsed));
+        }
+
+        if options.stats {
+            eprintln!("instructions: {}", stats.instructions);
+            eprintln!("inputs: {}", stats.inputs);
+            eprintln!("outputs: {}", stats.outputs);
+            eprintln!("peak mp: {}", stats.peak_mp);
         }
-    }
 
-    // Start the virtual machine
-    fn start(&mut self) {
-        while self.ip < self.program.len() {
-            self.execute();
-            self.ip += 1;
+        if let Some(digest) = vm.digest() {
+            eprintln!("{}: {} bytes, fnv-1a {:016x}", file_path, stats.written, digest);
         }
+
+        if options.config.profile {
+            vm.profile(&mut stderr(), 20).unwrap_or_else(|_| error("error: failed to write the profile"));
+        }
+
+        if options.config.loop_profile {
+            vm.loop_profile(&mut stderr(), 20)
+                .unwrap_or_else(|_| error("error: failed to write the loop profile"));
+        }
+
+        if options.dump {
+            vm.dump(&mut stderr()).unwrap_or_else(|_| error("error: failed to write the dump"));
+        }
+
+        if options.hist {
+            vm.histogram(&mut stderr()).unwrap_or_else(|_| error("error: failed to write the histogram"));
+        }
+
+        if options.fork {
+            report.push((file_path.to_string(), stats, elapsed));
+        }
+
+        if options.shared_tape {
+            shared = Some(vm);
+        }
+    }
+
+    if options.fork {
+        print_report(&report);
     }
+
+    if programs.len() > 1 {
+        eprintln!("{}/{} programs succeeded", programs.len() - failed, programs.len());
+    }
+
+    failed == 0
 }
 
-fn main() {
-    let mut files = 0;
+const USAGE: &str = "usage: brainfuck [--dialect {classic,std,musl}]
+                 [--tape-size N] [--cell-size {8,16,32}] [--signed]
+                 [--bounds {wrap,error}] [--tape {ring,line}] [--grow]
+                 [--max-tape-size N] [--input-file PATH] [--random-input SEED]
+                 [--output-file PATH] [--tail N] [--output-buffer-size N]
+                 [--cache-dir DIR] [--save-state PATH] [--load-state PATH]
+                 [--bang-input] [--input-eager] [--strict]
+                 [--semicolon-comments] [--tab-width N] [--max-nesting N]
+                 [--open-char C] [--close-char C] [--byte-positions]
+                 [--no-optimize] [--unroll N] [--warn]
+                 [--eof {zero,neg-one,unchanged}] [--numeric-output]
+                 [--numeric-input] [--annotate-output] [--latin1] [--crlf]
+                 [--echo-input] [--output-digest] [--saturate] [--max-steps N]
+                 [--max-output N] [--comment-loop] [--enable-debug-inst]
+                 [--enable-dual-tape] [--dump] [--hist] [--stats] [--time]
+                 [--dump-on-interrupt] [--debug] [--break-on-output BYTE]
+                 [--step-delay MS] [--repl] [--shared-tape] [--fork] [--concat]
+                 [--profile] [--loop-profile] [--trace] [--emit-c]
+                 [--emit-rust] [--emit-wat] [--dump-bytecode] [--count-only]
+                 [--help] [--version] [FILE...]";
+
+const HELP: &str = "commands:
+  +                increment the current cell
+  -                decrement the current cell
+  >                move the pointer one cell to the right
+  <                move the pointer one cell to the left
+  ,                read a byte of input into the current cell
+  .                write the current cell as a byte of output
+  [                jump past the matching ']' if the current cell is zero
+  ]                jump back to the matching '[' unless the current cell is zero
+
+extensions:
+  #                print the cells around the pointer (--enable-debug-inst)
+  $                print the position of the pointer (--enable-debug-inst)
+  @                switch to the other tape (--enable-dual-tape)
+  ;                comment out the rest of the line (--semicolon-comments)
+  !                start the input embedded in the program (--bang-input)
+
+options:
+  --dialect NAME         preset the tape, cells and end of input for classic,
+                         std or musl
+  --tape-size N          cells on the tape (default 30000)
+  --cell-size N          bits in a cell, 8, 16 or 32 (default 8)
+  --signed               use signed cells
+  --bounds MODE          wrap or error when moving off the tape (default wrap)
+  --tape SHAPE           ring or line, the same as --bounds wrap and error
+                         (default ring)
+  --grow                 extend the tape when moving past its right end
+  --max-tape-size N      cells a growing tape stops at (default 67108864)
+  --input-file PATH      read input from a file instead of standard input
+  --random-input SEED    read an endless stream of pseudo-random bytes, the
+                         same for every run with the same nonzero seed
+  --output-file PATH     write output to a file instead of standard output
+  --tail N               only write the last N bytes of output, once the
+                         program ends
+  --output-buffer-size N bytes of output held back before writing them, still
+                         flushed before every input (default 8192)
+  --cache-dir DIR        keep compiled programs in DIR to skip compiling the
+                         same source again
+  --save-state PATH      save the tapes and the position in the program once
+                         it ends or is interrupted
+  --load-state PATH      resume the program from a saved state
+  --bang-input           treat everything after '!' as input
+  --input-eager          read all input before the program starts
+  --strict               reject characters which are not commands
+  --semicolon-comments   ignore everything from ';' to the end of the line
+  --tab-width N          columns a tab advances in locations (default 1)
+  --max-nesting N        deepest loops may be nested (default unlimited)
+  --open-char C          the character starting a loop (default '[')
+  --close-char C         the character ending a loop (default ']')
+  --byte-positions       count columns in bytes rather than characters
+  --no-optimize          run every command as written
+  --unroll N             unroll loops running at most N times (default 0)
+  --warn                 warn about code which is likely a mistake
+  --eof MODE             zero, neg-one or unchanged at the end of input
+                         (default zero)
+  --numeric-output       write cells as numbers
+  --numeric-input        read cells as numbers separated by whitespace
+  --annotate-output      write cells with their value and character
+  --latin1               write bytes from 0x80 up as Latin-1 characters
+  --crlf                 write newlines as CRLF
+  --echo-input           write consumed input bytes to the output
+  --output-digest        print the number of bytes written and their FNV-1a
+                         hash once the program ends
+  --saturate             clamp cells at their bounds instead of wrapping
+  --max-steps N          stop after N instructions (default unlimited)
+  --max-output N         stop after N bytes of output (default unlimited)
+  --comment-loop         ignore a loop at the very start of the program
+  --enable-debug-inst    enable the '#' and '$' extensions
+  --enable-dual-tape     enable the '@' extension
+  --dump                 print the tape once the program ends
+  --hist                 count the visited cells holding each value once the
+                         program ends
+  --stats                print instruction and I/O counts
+  --time                 print how long the program ran
+  --dump-on-interrupt    print the cells when interrupted with Ctrl-C
+  --debug                step through the program interactively
+  --break-on-output BYTE run until the byte is about to be written, then
+                         debug
+  --repl                 read and run programs line by line
+  --shared-tape          run every file on the same tape
+  --fork                 run every file on a fresh tape and compare them in
+                         a table at the end
+  --concat               run every file as a single program
+  --profile              print the most executed instructions
+  --loop-profile         print the loops which repeated the most
+  --trace                print every instruction as it executes
+  --step-delay MS        pause MS milliseconds after every instruction, to
+                         watch the output appear or the --trace go by
+  --emit-c               translate the program to C
+  --emit-rust            translate the program to Rust
+  --emit-wat             translate the program to WebAssembly text
+  --dump-bytecode        print the compiled instructions
+  --count-only           print the number of instructions and loops and the
+                         deepest nesting without running the program
+  --help, -h             print this help
+  --version              print the version";
 
-    for file_path in env::args().skip(1) {
-        let mut vm = Vm::new(compile(&file_path));
-        vm.start();
+const VERSION: &str = "0.1.0";
 
-        files += 1;
+// Set up the tape, cells and end of input the way a well known environment
+// does. Müller's original left moving off the tape unchecked, so it is an
+// error here rather than a wrap
+fn dialect(options: &mut Options, name: &str) {
+    let (eof, bounds) = match name {
+        "classic" => (Eof::Unchanged, Bounds::Wrap),
+        "std" => (Eof::Zero, Bounds::Error),
+        "musl" => (Eof::Unchanged, Bounds::Error),
+        _ => error(&format!("error: --dialect expects one of classic, std or musl
+{}", USAGE))
+    };
+
+    options.config.tape_size = TAPE_LENGTH;
+    options.config.eof = eof;
+    options.config.bounds = bounds;
+    options.cell_size = 8;
+    options.signed = false;
+}
+
+// Collect the options from the command line arguments. Anything which is not
+// a flag is a file to run, as is everything after '--'
+fn parse_args(args: impl Iterator<Item = String>) -> Options {
+    let args: Vec<String> = args.collect();
+    let mut options = Options {
+        files: vec![],
+        compile: CompileOptions::default(),
+        config: Config::default(),
+        cell_size: 8,
+        signed: false,
+        optimize: true,
+        unroll: 0,
+        warn: false,
+        dump: false,
+        hist: false,
+        stats: false,
+        time: false,
+        dump_on_interrupt: false,
+        debug: false,
+        break_on_output: None,
+        step_delay: None,
+        repl: false,
+        shared_tape: false,
+        fork: false,
+        concat: false,
+        bang_input: false,
+        input_eager: false,
+        emit: None,
+        input_file: None,
+        random_input: None,
+        output_file: None,
+        tail: None,
+        cache_dir: None,
+        save_state: None,
+        load_state: None
+    };
+
+    // A dialect only sets the defaults, so the flags given alongside it win
+    // wherever they appear
+    let flags = args.iter().take_while(|arg| *arg != "--");
+    if let Some(name) = flags.skip_while(|arg| *arg != "--dialect").nth(1) {
+        dialect(&mut options, name);
     }
 
-    if files == 0 {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tape-size" => {
+                options.config.tape_size = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n >= 1)
+                    .unwrap_or_else(|| error(&format!("error: --tape-size expects a positive integer
+{}", USAGE)));
+            },
+            "--cell-size" => {
+                options.cell_size = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| [8, 16, 32].contains(n))
+                    .unwrap_or_else(|| error(&format!("error: --cell-size expects one of 8, 16 or 32
+{}", USAGE)));
+            },
+            "--signed" => options.signed = true,
+            "--max-steps" => {
+                options.config.max_steps = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| error(&format!("error: --max-steps expects an integer
+{}", USAGE))));
+            },
+            "--tab-width" => {
+                options.compile.tab_width = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n >= 1)
+                    .unwrap_or_else(|| error(&format!("error: --tab-width expects a positive integer
+{}", USAGE)));
+            },
+            "--open-char" | "--close-char" => {
+                let c = args.next()
+                    .filter(|c| c.chars().count() == 1)
+                    .and_then(|c| c.chars().next())
+                    .filter(|c| !"+-<>,.\n".contains(*c))
+                    .unwrap_or_else(|| error(&format!("error: {} expects a character which is not a command
+{}", arg, USAGE)));
+
+                if arg == "--open-char" {
+                    options.compile.open = c;
+                } else {
+                    options.compile.close = c;
+                }
+            },
+            "--max-nesting" => {
+                options.compile.max_nesting = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| error(&format!("error: --max-nesting expects an integer
+{}", USAGE))));
+            },
+            "--unroll" => {
+                options.unroll = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| error(&format!("error: --unroll expects an integer
+{}", USAGE)));
+            },
+            "--max-output" => {
+                options.config.max_output = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| error(&format!("error: --max-output expects an integer
+{}", USAGE))));
+            },
+            "--dialect" => {
+                if args.next().is_none() {
+                    error(&format!("error: --dialect expects one of classic, std or musl
+{}", USAGE));
+                }
+            },
+            "--grow" => options.config.bounds = Bounds::Grow,
+            "--max-tape-size" => {
+                options.config.max_tape_size = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n >= 1)
+                    .unwrap_or_else(|| error(&format!("error: --max-tape-size expects a positive integer
+{}", USAGE)));
+            },
+            "--bounds" => {
+                options.config.bounds = match args.next().as_deref() {
+                    Some("wrap") => Bounds::Wrap,
+                    Some("error") => Bounds::Error,
+                    _ => error(&format!("error: --bounds expects one of wrap or error
+{}", USAGE))
+                };
+            },
+            "--tape" => {
+                options.config.bounds = match args.next().as_deref() {
+                    Some("ring") => Bounds::Wrap,
+                    Some("line") => Bounds::Error,
+                    _ => error(&format!("error: --tape expects one of ring or line
+{}", USAGE))
+                };
+            },
+            "--input-file" => {
+                options.input_file = Some(args.next().unwrap_or_else(|| {
+                    error(&format!("error: --input-file expects a path
+{}", USAGE))
+                }));
+            },
+            "--save-state" => {
+                options.save_state = Some(args.next().unwrap_or_else(|| {
+                    error(&format!("error: --save-state expects a path
+{}", USAGE))
+                }));
+            },
+            "--load-state" => {
+                options.load_state = Some(args.next().unwrap_or_else(|| {
+                    error(&format!("error: --load-state expects a path
+{}", USAGE))
+                }));
+            },
+            "--cache-dir" => {
+                options.cache_dir = Some(args.next().unwrap_or_else(|| {
+                    error(&format!("error: --cache-dir expects a path
+{}", USAGE))
+                }));
+            },
+            "--random-input" => {
+                // A xorshift generator seeded with zero only ever gives zero
+                options.random_input = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n != 0)
+                    .unwrap_or_else(|| error(&format!("error: --random-input expects a nonzero seed
+{}", USAGE))));
+            },
+            "--output-buffer-size" => {
+                options.config.output_buffer_size = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| error(&format!("error: --output-buffer-size expects an integer
+{}", USAGE)));
+            },
+            "--tail" => {
+                options.tail = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n >= 1)
+                    .unwrap_or_else(|| error(&format!("error: --tail expects a positive integer
+{}", USAGE))));
+            },
+            "--output-file" => {
+                options.output_file = Some(args.next().unwrap_or_else(|| {
+                    error(&format!("error: --output-file expects a path
+{}", USAGE))
+                }));
+            },
+            "--eof" => {
+                options.config.eof = match args.next().as_deref() {
+                    Some("zero") => Eof::Zero,
+                    Some("neg-one") => Eof::NegOne,
+                    Some("unchanged") => Eof::Unchanged,
+                    _ => error(&format!("error: --eof expects one of zero, neg-one or unchanged
+{}", USAGE))
+                };
+            },
+            "--numeric-output" => options.config.numeric_output = true,
+            "--numeric-input" => options.config.numeric_input = true,
+            "--annotate-output" => options.config.annotate_output = true,
+            "--comment-loop" => options.compile.comment_loop = true,
+            "--enable-debug-inst" => options.compile.debug_inst = true,
+            "--enable-dual-tape" => options.compile.dual_tape = true,
+            "--strict" => options.compile.strict = true,
+            "--semicolon-comments" => options.compile.semicolon_comments = true,
+            "--byte-positions" => options.compile.byte_positions = true,
+            "--warn" => options.warn = true,
+            "--no-optimize" => {
+                options.compile.fold = false;
+                options.optimize = false;
+            },
+            "--latin1" => options.config.latin1 = true,
+            "--crlf" => options.config.crlf = true,
+            "--echo-input" => options.config.echo_input = true,
+            "--output-digest" => options.config.digest = true,
+            "--saturate" => {
+                options.config.saturate = true;
+                options.optimize = false;
+            },
+            "--profile" => options.config.profile = true,
+            "--loop-profile" => options.config.loop_profile = true,
+            "--trace" => options.config.trace = true,
+            "--dump" => options.dump = true,
+            "--hist" => options.hist = true,
+            "--stats" => options.stats = true,
+            "--time" => options.time = true,
+            "--dump-on-interrupt" => options.dump_on_interrupt = true,
+            "--debug" => options.debug = true,
+            "--break-on-output" => {
+                options.debug = true;
+                options.break_on_output = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| error(&format!("error: --break-on-output expects a byte value
+{}", USAGE))));
+            },
+            "--step-delay" => {
+                options.step_delay = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .map(Duration::from_millis)
+                    .unwrap_or_else(|| error(&format!("error: --step-delay expects an integer
+{}", USAGE))));
+            },
+            "--repl" => options.repl = true,
+            "--shared-tape" => options.shared_tape = true,
+            "--fork" => options.fork = true,
+            "--concat" => options.concat = true,
+            "--bang-input" => options.bang_input = true,
+            "--input-eager" => options.input_eager = true,
+            "--emit-c" => options.emit = Some(Emit::C),
+            "--emit-rust" => options.emit = Some(Emit::Rust),
+            "--emit-wat" => options.emit = Some(Emit::Wat),
+            "--dump-bytecode" => options.emit = Some(Emit::Bytecode),
+            "--count-only" => options.emit = Some(Emit::Counts),
+            "--help" | "-h" => {
+                println!("{}\n\n{}", USAGE, HELP);
+                process::exit(0);
+            },
+            "--version" => {
+                println!("brainfuck {}", VERSION);
+                process::exit(0);
+            },
+            "--" => options.files.extend(args.by_ref()),
+            flag if flag.starts_with('-') && flag != "-" => {
+                error(&format!("error: unknown flag '{}'
+{}", flag, USAGE))
+            },
+            _ => options.files.push(arg)
+        }
+    }
+
+    if options.random_input.is_some() && options.input_eager {
+        error(&format!("error: --random-input never ends, so it cannot all be read upfront
+{}", USAGE));
+    }
+
+    let stateful = options.save_state.is_some() || options.load_state.is_some();
+    if stateful && options.files.len() > 1 && !options.concat {
+        error(&format!("error: a state belongs to a single program, so only one can be run with it
+{}", USAGE));
+    }
+
+    if options.fork && options.shared_tape {
+        error(&format!("error: --fork runs every file on a fresh tape, so it cannot share one
+{}", USAGE));
+    }
+
+    // The options of the tape only reach the VM, while the translations are
+    // fixed to the defaults
+    let translated = matches!(options.emit, Some(Emit::C) | Some(Emit::Rust) | Some(Emit::Wat));
+    if translated && (options.cell_size != 8 || options.config.bounds != Bounds::Wrap ||
+                      options.config.eof != Eof::Zero || options.config.saturate) {
+        error(&format!("error: a translated program only runs on a ring of wrapping 8-bit cells which read zero at the end of input
+{}", USAGE));
+    }
+
+    if options.compile.open == options.compile.close {
+        error(&format!("error: loops cannot start and end with the same character
+{}", USAGE));
+    }
+
+    if options.files.is_empty() && !options.repl {
         error(&format!("error: no input files were provided
-usage: brainfuck [FILE]"));
+{}", USAGE));
+    }
+
+    options
+}
+
+fn main() {
+    let options = parse_args(env::args().skip(1));
+    if options.dump_on_interrupt {
+        catch_interrupt();
+    }
+
+    let succeeded = match (options.cell_size, options.signed) {
+        (16, false) => run::<u16>(&options),
+        (32, false) => run::<u32>(&options),
+        (16, true) => run::<i16>(&options),
+        (32, true) => run::<i32>(&options),
+        (_, true) => run::<i8>(&options),
+        _ => run::<u8>(&options)
+    };
+
+    if !succeeded {
+        process::exit(1);
     }
 }
@@ -0,0 +1,240 @@
+extern crate brainfuck;
+
+use std::env;
+use std::io;
+
+use brainfuck::{check_brackets, compile, compile_reader, compile_spans, decode, encode, optimize, unroll, validate};
+use brainfuck::{Bounds, CompileErrorKind, CompileOptions, Config, Fault, Machine, Program, RunError, Vm};
+
+// A small xorshift generator, so the fuzzer needs nothing but the library
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+// Every combination of the compile options which change what is accepted
+fn all_options() -> Vec<CompileOptions> {
+    let mut options = vec![];
+    for bits in 0..32 {
+        options.push(CompileOptions {
+            fold: bits & 1 != 0,
+            comment_loop: bits & 2 != 0,
+            debug_inst: bits & 4 != 0,
+            strict: bits & 8 != 0,
+            dual_tape: bits & 16 != 0,
+            ..CompileOptions::default()
+        });
+    }
+    options
+}
+
+// Compile a source with every set of options. A successful compile must
+// produce a valid program, both before and after optimizing it, which
+// decodes back to itself once encoded. Every instruction has a span which
+// starts where it does and never ends before it
+fn check(source: &str, options: &[CompileOptions]) {
+    for options in options {
+        if let Ok((program, spans)) = compile_spans(source, options) {
+            if let Err(err) = validate(&program.code) {
+                panic!("compiled an invalid program from {:?}: {}", source, err);
+            }
+
+            let spanned = spans.len() == program.code.len() && spans.iter().enumerate()
+                .all(|(i, span)| span.start == program.position(i) && span.start <= span.end);
+            if !spanned {
+                panic!("compiled the wrong spans from {:?}", source);
+            }
+
+            let program = optimize(program);
+            if let Err(err) = validate(&program.code) {
+                panic!("optimized to an invalid program from {:?}: {}", source, err);
+            }
+
+            let encoded = encode(&program);
+            if decode(&encoded).map(|decoded| encode(&decoded)) != Some(encoded) {
+                panic!("encoding changed the program from {:?}", source);
+            }
+        }
+    }
+}
+
+// Checking the brackets of a source on their own must find a problem exactly
+// when compiling it runs into one, including the one compiling stopped at
+fn brackets(source: &str, options: &CompileOptions) {
+    let same = match (compile(source, options), check_brackets(source, options)) {
+        (Ok(_), Ok(())) => true,
+        (Err(ref err), Err(ref errors)) if is_bracket(err.kind) => {
+            errors.iter().any(|other| (other.line, other.column) == (err.line, err.column))
+        },
+        (Err(ref err), Ok(())) => !is_bracket(err.kind),
+        (Err(_), Err(_)) => true,
+        (Ok(_), Err(_)) => false
+    };
+
+    if !same {
+        panic!("checking the brackets of {:?} disagrees with compiling it", source);
+    }
+}
+
+fn is_bracket(kind: CompileErrorKind) -> bool {
+    kind == CompileErrorKind::UnbalancedClose || kind == CompileErrorKind::UnterminatedOpen
+}
+
+// Compiling a source while reading it must give the same program, or fail at
+// the same place, as compiling it all at once
+fn stream(source: &str, options: &CompileOptions) {
+    let same = match (compile(source, options), compile_reader(source.as_bytes(), options)) {
+        (Ok(a), Ok(b)) => encode(&a) == encode(&b),
+        (Err(a), Err(b)) => (a.kind, a.line, a.column) == (b.kind, b.line, b.column),
+        _ => false
+    };
+
+    if !same {
+        panic!("streaming changed how {:?} compiles", source);
+    }
+}
+
+// What a program did when run: the pointer, the tape and the output if it
+// finished, or whether it moved off the tape
+type Outcome = Result<(usize, Vec<u8>, Vec<u8>), bool>;
+
+// Run a program on a small tape for a bounded number of steps, or nothing if
+// it did not finish in time. A tape which grows may grow by different amounts
+// for the same cells, so the zeros at its end are left out
+fn execute(program: Program, bounds: Bounds) -> Option<Outcome> {
+    let config = Config {
+        tape_size: 64,
+        max_tape_size: 256,
+        bounds,
+        max_steps: Some(100000),
+        ..Config::default()
+    };
+
+    let mut output = vec![];
+    let (result, pointer, mut tape) = {
+        let mut vm: Vm<u8> = Vm::new(program, &config, Box::new(io::empty()), Box::new(&mut output));
+        let result = vm.run_to_completion();
+        (result, vm.pointer(), vm.tape().to_vec())
+    };
+
+    match result {
+        Ok(_) => {},
+        Err(RunError::OutOfBounds { .. }) => return Some(Err(true)),
+        Err(RunError::StepLimit { .. }) => return None,
+        Err(_) => return Some(Err(false))
+    }
+
+    let length = tape.iter().rposition(|&cell| cell != 0).map_or(0, |index| index + 1);
+    tape.truncate(length);
+    Some(Ok((pointer, tape, output)))
+}
+
+// Run a program like 'execute' does, but on a machine with a tape of its own
+fn execute_on_machine(program: &Program, bounds: Bounds) -> Option<Outcome> {
+    let config = Config { bounds, max_steps: Some(100000), ..Config::default() };
+    let mut tape = [0u8; 64];
+    let mut output = vec![];
+    let (result, pointer) = {
+        let mut machine = Machine::new(program, &config, &mut tape, 0, &[][..], &mut output);
+        (machine.run(), machine.pointer())
+    };
+
+    match result {
+        Ok(()) => {},
+        Err(Fault::OutOfBounds { .. }) => return Some(Err(true)),
+        Err(Fault::StepLimit { .. }) => return None,
+        Err(_) => return Some(Err(false))
+    }
+
+    let length = tape.iter().rposition(|&cell| cell != 0).map_or(0, |index| index + 1);
+    Some(Ok((pointer, tape[..length].to_vec(), output)))
+}
+
+// An optimized program must leave the pointer, the tape and the output the
+// same as running every command one at a time does, with every kind of
+// bounds. It may only move off the tape if that does too, though it can
+// skip a move off the tape which is undone right away, like '<>'. A machine
+// only differs from the Vm in the tape it borrows and the bytes it reads and
+// writes, and must run both exactly like the Vm does on a tape which does
+// not grow. Unrolling either of them must not change what it does at all
+fn compare(source: &str) {
+    let options = CompileOptions { fold: false, ..CompileOptions::default() };
+    let program = match compile(source, &options) {
+        Ok(program) => program,
+        Err(_) => return
+    };
+
+    let optimized = optimize(program.clone());
+    for &bounds in &[Bounds::Wrap, Bounds::Error, Bounds::Grow] {
+        let naive = execute(program.clone(), bounds);
+        if let (Some(naive), Some(optimized)) = (naive, execute(optimized.clone(), bounds)) {
+            if naive != optimized && naive != Err(true) {
+                panic!("optimizing changed what {:?} does with {:?} bounds", source, bounds);
+            }
+        }
+
+        for program in [&program, &optimized] {
+            let unrolled = unroll(program.clone(), 16);
+            if let (Some(rolled), Some(unrolled)) = (execute(program.clone(), bounds), execute(unrolled, bounds)) {
+                if rolled != unrolled {
+                    panic!("unrolling changed what {:?} does with {:?} bounds", source, bounds);
+                }
+            }
+        }
+
+        if bounds == Bounds::Grow {
+            continue;
+        }
+
+        for program in [&program, &optimized] {
+            if execute_on_machine(program, bounds) != execute(program.clone(), bounds) {
+                panic!("a machine ran {:?} differently from the Vm with {:?} bounds", source, bounds);
+            }
+        }
+    }
+}
+
+// The interesting bytes turn up far more often than the rest
+const ALPHABET: &[u8] = b"+-<>,.[]#@ \n";
+
+fn main() {
+    let iterations = env::args().nth(1).and_then(|n| n.parse().ok()).unwrap_or(10000);
+    let mut random = Random(env::args().nth(2).and_then(|n| n.parse().ok()).unwrap_or(0x2545f491));
+    let options = all_options();
+
+    // Nesting this deep would overflow the stack of a recursive compiler
+    let depth = 1 << 20;
+    check(&("[".repeat(depth) + &"]".repeat(depth)), &options);
+    check(&"[".repeat(depth), &options);
+    check(&"]".repeat(depth), &options);
+
+    let mut bytes = vec![];
+    for _ in 0..iterations {
+        bytes.clear();
+        let length = random.next() % 256;
+        for _ in 0..length {
+            let byte = random.next();
+            bytes.push(if byte & 3 == 0 {
+                (byte >> 8) as u8
+            } else {
+                ALPHABET[(byte >> 8) as usize % ALPHABET.len()]
+            });
+        }
+
+        let source = String::from_utf8_lossy(&bytes);
+        check(&source, &options);
+        compare(&source);
+        for options in &options {
+            stream(&source, options);
+            brackets(&source, options);
+        }
+    }
+
+    println!("fuzzed {} inputs", iterations);
+}
@@ -0,0 +1,3588 @@
+// Built with '--cfg no_std' the crate only needs an allocator. It keeps the
+// compiler, the optimizer and 'Machine', which runs a program on a tape the
+// caller provides, while 'Vm' and everything reading files or streams needs std
+#![cfg_attr(no_std, no_std)]
+
+#[cfg(not(no_std))]
+extern crate core;
+
+#[cfg(no_std)]
+#[macro_use]
+extern crate alloc;
+
+use core::fmt;
+use core::convert::TryFrom;
+use core::ops::DerefMut;
+
+#[cfg(no_std)]
+use alloc::{string::{String, ToString}, vec::Vec};
+
+#[cfg(not(no_std))]
+use std::fs::File;
+#[cfg(not(no_std))]
+use std::mem;
+#[cfg(not(no_std))]
+use std::collections::{BTreeMap, VecDeque};
+#[cfg(not(no_std))]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(no_std))]
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+// Instructions for the VM
+//
+// The operand of a loop instruction is the index of its partner, so that
+// 'LoopStart' jumps past its 'LoopEnd' and 'LoopEnd' jumps back into the
+// body without ever searching the program. 'validate' checks that every
+// pair agrees with each other.
+#[derive(Debug, Clone)]
+pub enum Inst {
+    Inc(usize),
+    Dec(usize),
+    ShiftRight(usize),
+    ShiftLeft(usize),
+    Input(usize),
+    Output(usize),
+    LoopStart(usize),
+    LoopEnd(usize),
+    SetZero,
+
+    // Store a constant in the current cell, from a clear followed by '+' or
+    // '-'. The value wraps like the factor of 'MulAdd'
+    SetConst(usize),
+
+    // Add the current cell times 'factor' to the cell 'offset' away. The
+    // factor wraps like the cells do, so it is stored wide enough for any
+    // cell size
+    MulAdd { offset: isize, factor: usize },
+
+    // Add to the cell 'offset' away without moving the pointer, from '>+<'.
+    // The amount wraps like the factor of 'MulAdd'
+    AddAt { offset: isize, amount: usize },
+
+    // Add to each cell 'offset' away and then move the pointer by 'shift',
+    // from a straight run like '+>++>-<'. The amounts wrap like the factor of
+    // 'MulAdd'. Each delta keeps the line and column of the command which
+    // first reached its cell, and the last one is that of the final move, to
+    // blame for moving off the tape
+    Block { deltas: Vec<(isize, usize)>, shift: isize, positions: Vec<(usize, usize)> },
+
+    // Clear 'len' cells from the pointer onwards and leave the pointer on the
+    // last of them, from '[-]>[-]>[-]'
+    ClearRange { len: usize },
+
+    // Move the pointer to the nearest zero cell, from '[>]' and '[<]'
+    ScanRight,
+    ScanLeft,
+
+    // Print the cells around the pointer, from the '#' extension
+    Debug,
+
+    // Print the position of the pointer, from the '$' extension
+    PrintPointer,
+
+    // Switch to the other tape and its own pointer, from the '@' extension
+    SwapTape,
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Inst::*;
+
+        match *self {
+            Inc(amount) => write!(f, "add {}", amount),
+            Dec(amount) => write!(f, "sub {}", amount),
+            ShiftRight(amount) => write!(f, "shr {}", amount),
+            ShiftLeft(amount) => write!(f, "shl {}", amount),
+            Input(amount) => write!(f, "in {}", amount),
+            Output(amount) => write!(f, "out {}", amount),
+            LoopStart(end) => write!(f, "jz {}", end),
+            LoopEnd(start) => write!(f, "jnz {}", start),
+            SetZero => write!(f, "zero"),
+            SetConst(value) => write!(f, "set {}", value as isize),
+            MulAdd { offset, factor } => write!(f, "muladd {:+} {}", offset, factor as isize),
+            AddAt { offset, amount } => write!(f, "addat {:+} {}", offset, amount as isize),
+            Block { ref deltas, shift, .. } => {
+                write!(f, "block {:+}", shift)?;
+                for &(offset, amount) in deltas {
+                    write!(f, " {:+}:{}", offset, amount as isize)?;
+                }
+                Ok(())
+            },
+            ClearRange { len } => write!(f, "clear {}", len),
+            ScanRight => write!(f, "scanr"),
+            ScanLeft => write!(f, "scanl"),
+            Debug => write!(f, "debug"),
+            PrintPointer => write!(f, "pointer"),
+            SwapTape => write!(f, "swap"),
+        }
+    }
+}
+
+// A compiled program, along with the line and column in the source where
+// each instruction begins
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub code: Vec<Inst>,
+    pub positions: Vec<(usize, usize)>,
+}
+
+impl Program {
+    // Where an instruction begins in the source, or (0, 0) if it is unknown
+    pub fn position(&self, index: usize) -> (usize, usize) {
+        self.positions.get(index).copied().unwrap_or((0, 0))
+    }
+}
+
+// The part of the source an instruction was compiled from, from the line and
+// column of its first command to those of its last. They are the same unless
+// a run of commands was folded into the instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceSpan {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+}
+
+// The ways in which compiling a BF program can fail
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompileErrorKind {
+    UnbalancedClose,
+    UnterminatedOpen,
+    ReadFailed,
+    MismatchedJump(usize),
+    InvalidChar(char),
+    TooDeep(usize),
+}
+
+// An error in a BF program, along with where it occured
+#[derive(Debug)]
+pub struct CompileError {
+    pub path: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub kind: CompileErrorKind,
+
+    // The source around the error and any hints for fixing it, printed
+    // after the message. Empty if there is nothing to show
+    pub context: String,
+}
+
+impl CompileError {
+    fn new(kind: CompileErrorKind, line: usize, column: usize) -> Self {
+        Self {
+            path: None,
+            line,
+            column,
+            kind,
+            context: String::new()
+        }
+    }
+
+    // Show the lines of the source leading up to the error
+    fn excerpt(mut self, source: &mut impl Source) -> Self {
+        self.context = source.excerpt(self.line, self.column);
+        self
+    }
+}
+
+// The line at a location in the source and the two before it, with a marker
+// under the column. The source starts at line 'start', and lines before it
+// are left out
+fn excerpt(source: &str, start: usize, line: usize, column: usize) -> String {
+    let first = line.saturating_sub(2).max(start);
+
+    let mut excerpt = String::new();
+    for (index, text) in source.lines().enumerate().skip(first - start).take(line + 1 - first) {
+        excerpt += &format!("{:>5} | {}\n", index + start, text);
+    }
+
+    excerpt + &format!("{:>5} | {}^", "", " ".repeat(column.saturating_sub(1)))
+}
+
+// Where a program is compiled from, one character at a time
+trait Source {
+    // The next character, or nothing at the end and the kind of error if it
+    // could not be read
+    fn next(&mut self) -> Result<Option<char>, CompileErrorKind>;
+
+    // The source around a location, or nothing if it is no longer known
+    fn excerpt(&mut self, line: usize, column: usize) -> String;
+}
+
+// A source which is entirely in memory
+struct Text<'a> {
+    source: &'a str,
+    chars: core::str::Chars<'a>,
+}
+
+impl<'a> Source for Text<'a> {
+    fn next(&mut self) -> Result<Option<char>, CompileErrorKind> {
+        Ok(self.chars.next())
+    }
+
+    fn excerpt(&mut self, line: usize, column: usize) -> String {
+        excerpt(self.source, 1, line, column)
+    }
+}
+
+// A source decoded from UTF-8 as it is read, which only holds on to the last
+// three lines for excerpts
+#[cfg(not(no_std))]
+struct Stream<R> {
+    reader: R,
+    recent: VecDeque<Recent>,
+
+    // The line number of the first line in 'recent'
+    start: usize,
+}
+
+// The columns of a line kept for excerpts, which are only the last few of a
+// long line, so a source of one huge line fits in a fixed amount of memory
+#[cfg(not(no_std))]
+#[derive(Default)]
+struct Recent {
+    text: String,
+    columns: usize,
+    skipped: usize,
+}
+
+// How many columns are kept before the end of a long line, and read past an
+// error on it
+#[cfg(not(no_std))]
+const EXCERPT_COLUMNS: usize = 80;
+
+#[cfg(not(no_std))]
+impl<R: BufRead> Stream<R> {
+    fn decode(&mut self) -> io::Result<Option<char>> {
+        // Nearly every character of a program is ASCII
+        let first = match self.reader.fill_buf()?.first() {
+            Some(&byte) => byte,
+            None => return Ok(None)
+        };
+
+        if first < 0x80 {
+            self.reader.consume(1);
+            return Ok(Some(first as char));
+        }
+
+        self.reader.consume(1);
+        let mut bytes = [first, 0, 0, 0];
+        let length = match first {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            _ => 4
+        };
+
+        self.reader.read_exact(&mut bytes[1..length])?;
+        std::str::from_utf8(&bytes[..length]).ok()
+            .and_then(|c| c.chars().next())
+            .map(Some)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "source is not UTF-8"))
+    }
+}
+
+#[cfg(not(no_std))]
+impl<R: BufRead> Source for Stream<R> {
+    fn next(&mut self) -> Result<Option<char>, CompileErrorKind> {
+        let c = self.decode().map_err(|_| CompileErrorKind::ReadFailed)?;
+        match c {
+            Some('\n') => {
+                if self.recent.len() == 3 {
+                    self.recent.pop_front();
+                    self.start += 1;
+                }
+                self.recent.push_back(Recent::default());
+            },
+
+            Some(c) => {
+                let line = self.recent.back_mut().expect("the current line is always kept");
+                if line.columns == 2 * EXCERPT_COLUMNS {
+                    let cut = line.text.char_indices().nth(EXCERPT_COLUMNS)
+                        .map_or(line.text.len(), |(index, _)| index);
+                    line.text.drain(..cut);
+                    line.columns -= EXCERPT_COLUMNS;
+                    line.skipped += EXCERPT_COLUMNS;
+                }
+
+                line.text.push(c);
+                line.columns += 1;
+            },
+
+            None => {}
+        }
+
+        Ok(c)
+    }
+
+    // Some of the rest of the line with the error is read as well, and every
+    // line is shown from the first column still kept of that line
+    fn excerpt(&mut self, line: usize, column: usize) -> String {
+        let mut rest = String::new();
+        for _ in 0..EXCERPT_COLUMNS {
+            match self.decode() {
+                Ok(Some(c)) if c != '\n' => rest.push(c),
+                _ => break
+            }
+        }
+
+        if let Some(last) = self.recent.back_mut() {
+            last.text += &rest;
+        }
+
+        let from = match self.recent.get(line.wrapping_sub(self.start)) {
+            Some(recent) if column > recent.skipped => recent.skipped,
+            _ => return String::new()
+        };
+
+        let first = line.saturating_sub(2).max(self.start);
+        let marker = if from > 0 { "..." } else { "" };
+        let width = 3 * EXCERPT_COLUMNS;
+
+        let mut text = String::new();
+        for recent in self.recent.iter().skip(first - self.start).take(line + 1 - first) {
+            let padding = recent.skipped.saturating_sub(from).min(width);
+            let shown = recent.text.chars().skip(from.saturating_sub(recent.skipped));
+            text += marker;
+            text += &" ".repeat(padding);
+            text.extend(shown.take(width - padding));
+            text.push('\n');
+        }
+
+        excerpt(&text, first, line, column - from + marker.len())
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use CompileErrorKind::*;
+
+        let path = self.path.as_deref().unwrap_or("<source>");
+        match self.kind {
+            UnbalancedClose =>
+                write!(f, "{}:{}:{}: Unbalanced ']', no matching '[' was found before it",
+                       path, self.line, self.column),
+            UnterminatedOpen =>
+                write!(f, "{}:{}:{}: Unterminated '['", path, self.line, self.column),
+            ReadFailed =>
+                write!(f, "error: failed to read file '{}'", path),
+            MismatchedJump(index) =>
+                write!(f, "{}: Mismatched jump at instruction {}", path, index),
+            InvalidChar(c) =>
+                write!(f, "{}:{}:{}: Invalid character {:?}", path, self.line, self.column, c),
+            TooDeep(limit) =>
+                write!(f, "{}:{}:{}: Loops are nested deeper than the limit of {}",
+                       path, self.line, self.column, limit),
+        }?;
+
+        if !self.context.is_empty() {
+            write!(f, "\n{}", self.context)?;
+        }
+
+        Ok(())
+    }
+}
+
+// The options which change how a BF program is compiled
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    // Fold runs of the same command into a single instruction
+    pub fold: bool,
+
+    // Discard a loop at the very start of the program as a comment
+    pub comment_loop: bool,
+
+    // Compile '#' and '$' to debug instructions instead of treating them as
+    // comments
+    pub debug_inst: bool,
+
+    // Reject any character which is not a command or whitespace
+    pub strict: bool,
+
+    // Compile '@' to switch between two tapes. Programs using this are not
+    // portable to any other BF implementation
+    pub dual_tape: bool,
+
+    // The number of columns a tab advances by in error locations
+    pub tab_width: usize,
+
+    // Count columns in bytes rather than characters, for tools which map
+    // locations back to byte offsets in the line
+    pub byte_positions: bool,
+
+    // Ignore everything from ';' to the end of the line, commands included
+    pub semicolon_comments: bool,
+
+    // The deepest loops may be nested, for passes which recurse into loop
+    // bodies. Unlimited if none
+    pub max_nesting: Option<usize>,
+
+    // The characters which start and end a loop, for embedding programs where
+    // '[' and ']' mean something else
+    pub open: char,
+    pub close: char,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            fold: true,
+            comment_loop: false,
+            debug_inst: false,
+            strict: false,
+            dual_tape: false,
+            tab_width: 1,
+            byte_positions: false,
+            semicolon_comments: false,
+            max_nesting: None,
+            open: '[',
+            close: ']'
+        }
+    }
+}
+
+// The ways in which running a BF program can fail
+#[cfg(not(no_std))]
+#[derive(Debug)]
+pub enum RunError {
+    Io(io::Error),
+    StepLimit { ip: usize, mp: usize },
+    OutputLimit { ip: usize, limit: u64 },
+    Interrupted { ip: usize, mp: usize },
+    OutOfBounds { ip: usize, line: usize, column: usize },
+
+    // A program which did not pass 'validate'
+    Invalid(CompileError),
+}
+
+#[cfg(not(no_std))]
+impl From<io::Error> for RunError {
+    fn from(err: io::Error) -> Self {
+        RunError::Io(err)
+    }
+}
+
+#[cfg(not(no_std))]
+impl From<CompileError> for RunError {
+    fn from(err: CompileError) -> Self {
+        RunError::Invalid(err)
+    }
+}
+
+#[cfg(not(no_std))]
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RunError::Io(ref err) => write!(f, "{}", err),
+            RunError::StepLimit { ip, mp } =>
+                write!(f, "step limit exceeded at ip = {}, mp = {}", ip, mp),
+            RunError::OutputLimit { ip, limit } =>
+                write!(f, "output limit of {} bytes exceeded at ip = {}", limit, ip),
+            RunError::Interrupted { ip, mp } =>
+                write!(f, "interrupted at ip = {}, mp = {}", ip, mp),
+            RunError::OutOfBounds { ip, line, column } =>
+                write!(f, "{}:{}: memory pointer moved outside of the tape (ip = {})",
+                       line, column, ip),
+            RunError::Invalid(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+// If the last instruction in the "bytecode" is of the same type as
+// the one to be appended, then the amount of the last instruction is
+// increased instead, and its span extended to the command at 'at'
+macro_rules! sized_inst {
+    ($output: expr, $type: tt, $fold: expr, $ends: expr, $at: expr) => {{
+        match $output.last_mut() {
+            Some(&mut $type(ref mut n)) if $fold => {
+                *n += 1;
+                if let Some(end) = $ends.last_mut() {
+                    *end = $at;
+                }
+                continue;
+            },
+            _ => $output.push($type(1))
+        }
+    }};
+}
+
+// Compile a BF program to an instruction chunk for the turing machine. Any
+// input either compiles or returns an error, without panicking or exiting,
+// and loops are matched without recursion so no nesting depth is too deep
+pub fn compile(source: &str, options: &CompileOptions) -> Result<Program, CompileError> {
+    compile_spans(source, options).map(|(program, _)| program)
+}
+
+// Compile a BF program along with the span of the source each instruction
+// came from, for tools which point at the command being run
+pub fn compile_spans(source: &str,
+                     options: &CompileOptions) -> Result<(Program, Vec<SourceSpan>), CompileError> {
+    compile_from(&mut Text { source, chars: source.chars() }, options)
+}
+
+// Compile a BF program while reading it, so only the program and the end of
+// the last few lines of the source are ever held in memory. An unterminated
+// '[' from before those is reported without an excerpt of the source
+#[cfg(not(no_std))]
+pub fn compile_reader(reader: impl Read, options: &CompileOptions) -> Result<Program, CompileError> {
+    let mut stream = Stream {
+        reader: BufReader::new(reader),
+        recent: VecDeque::from(vec![Recent::default()]),
+        start: 1
+    };
+
+    compile_from(&mut stream, options).map(|(program, _)| program)
+}
+
+fn compile_from(source: &mut impl Source,
+                options: &CompileOptions) -> Result<(Program, Vec<SourceSpan>), CompileError> {
+    let mut output = vec![];
+    let mut positions = vec![];
+    let mut ends = vec![];
+    let mut loops = vec![];
+    let mut index = 0;
+
+    // The column of each character is where the previous one started plus
+    // however many columns the previous one was wide
+    let mut line = 1;
+    let mut column = 0;
+    let mut width = 1;
+    let mut comment = false;
+
+    use Inst::*;
+    loop {
+        let c = match source.next() {
+            Ok(Some(c)) => c,
+            Ok(None) => break,
+            Err(kind) => return Err(CompileError::new(kind, line, column))
+        };
+
+        column += width;
+        width = match c {
+            '\t' => options.tab_width,
+            _ if options.byte_positions => c.len_utf8(),
+            _ => 1
+        };
+
+        match c {
+            _ if comment && c != '\n' => continue,
+            ';' if options.semicolon_comments => {
+                comment = true;
+                continue;
+            },
+            '+' => sized_inst!(output, Inc, options.fold, ends, (line, column)),
+            '-' => sized_inst!(output, Dec, options.fold, ends, (line, column)),
+            '>' => sized_inst!(output, ShiftRight, options.fold, ends, (line, column)),
+            '<' => sized_inst!(output, ShiftLeft, options.fold, ends, (line, column)),
+            ',' => sized_inst!(output, Input, options.fold, ends, (line, column)),
+            '.' => sized_inst!(output, Output, options.fold, ends, (line, column)),
+            c if c == options.open => {
+                if let Some(limit) = options.max_nesting.filter(|&limit| loops.len() >= limit) {
+                    return Err(CompileError::new(CompileErrorKind::TooDeep(limit), line, column)
+                               .excerpt(source));
+                }
+
+                loops.push((index, line, column));
+                output.push(LoopStart(index));
+            },
+            '#' if options.debug_inst => output.push(Debug),
+            '$' if options.debug_inst => output.push(PrintPointer),
+            '@' if options.dual_tape => output.push(SwapTape),
+            c if c == options.close => match loops.pop() {
+                Some((0, _, _)) if options.comment_loop => {
+                    // Loop at the start of the program is a guaranted comment
+                    index = 0;
+                    loops.clear();
+                    output.clear();
+                    positions.clear();
+                    ends.clear();
+                    continue;
+                },
+                Some((i, _, _)) => {
+                    output[i] = LoopStart(index);
+                    output.push(LoopEnd(i));
+                },
+                None => return Err(CompileError::new(CompileErrorKind::UnbalancedClose,
+                                                     line, column).excerpt(source))
+            },
+            '\n' => {
+                line += 1;
+                column = 0;
+                comment = false;
+                continue;
+            },
+            c if options.strict && !c.is_whitespace() => {
+                return Err(CompileError::new(CompileErrorKind::InvalidChar(c), line, column)
+                           .excerpt(source));
+            },
+            _ => continue
+        }
+
+        positions.push((line, column));
+        ends.push((line, column));
+        index += 1;
+    }
+
+    // Report the outermost bracket which was never closed, pointing out the
+    // innermost one as well since that is usually the one missing its ']'
+    if let Some(&(_, line, column)) = loops.first() {
+        let mut err = CompileError::new(CompileErrorKind::UnterminatedOpen, line, column)
+            .excerpt(source);
+
+        if let Some(&(_, inner_line, inner_column)) = loops.last().filter(|_| loops.len() > 1) {
+            err.context += &format!("\nnote: {} loops are left open, the innermost at {}:{}",
+                                    loops.len(), inner_line, inner_column);
+
+            let inner = source.excerpt(inner_line, inner_column);
+            if !inner.is_empty() {
+                err.context += &format!("\n{}", inner);
+            }
+        }
+
+        return Err(err);
+    }
+
+    let spans = positions.iter().zip(ends)
+        .map(|(&start, end)| SourceSpan { start, end })
+        .collect();
+
+    Ok((Program { code: output, positions }, spans))
+}
+
+// Find every unmatched bracket of a BF program, instead of stopping at the
+// first one like 'compile' does. Nothing else about the program is checked.
+// Only the first few errors show the source around them, since a program
+// could have as many errors as characters
+pub fn check_brackets(source: &str, options: &CompileOptions) -> Result<(), Vec<CompileError>> {
+    let mut errors = vec![];
+    let mut loops = vec![];
+
+    let mut line = 1;
+    let mut column = 0;
+    let mut width = 1;
+    let mut comment = false;
+
+    for c in source.chars() {
+        column += width;
+        width = match c {
+            '\t' => options.tab_width,
+            _ if options.byte_positions => c.len_utf8(),
+            _ => 1
+        };
+
+        match c {
+            _ if comment && c != '\n' => {},
+            ';' if options.semicolon_comments => comment = true,
+            c if c == options.open => loops.push((line, column)),
+            c if c == options.close => match loops.pop() {
+                Some(_) => {},
+                None => errors.push(CompileError::new(CompileErrorKind::UnbalancedClose,
+                                                      line, column))
+            },
+            '\n' => {
+                line += 1;
+                column = 0;
+                comment = false;
+            },
+            _ => {}
+        }
+    }
+
+    errors.extend(loops.into_iter().map(|(line, column)| {
+        CompileError::new(CompileErrorKind::UnterminatedOpen, line, column)
+    }));
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    // The errors with an excerpt of the source
+    const EXCERPTS: usize = 10;
+
+    errors.sort_by_key(|err| (err.line, err.column));
+    let mut text = Text { source, chars: source.chars() };
+    Err(errors.into_iter().enumerate()
+        .map(|(index, err)| if index < EXCERPTS { err.excerpt(&mut text) } else { err })
+        .collect())
+}
+
+// Read and compile a BF program from a file, streaming it through the
+// compiler so a file of any size can be compiled
+#[cfg(not(no_std))]
+pub fn compile_file(file_path: &str, options: &CompileOptions) -> Result<Program, CompileError> {
+    let file = File::open(file_path).map_err(|_| CompileError {
+        path: Some(file_path.to_string()),
+        line: 0,
+        column: 0,
+        kind: CompileErrorKind::ReadFailed,
+        context: String::new()
+    })?;
+
+    compile_reader(file, options).map_err(|err| CompileError {
+        path: Some(file_path.to_string()),
+        ..err
+    })
+}
+
+// Compile a BF program that was already read, naming it in any error
+pub fn compile_source(source: &str,
+                      name: &str,
+                      options: &CompileOptions) -> Result<Program, CompileError> {
+    compile(source, options).map_err(|err| CompileError {
+        path: Some(name.to_string()),
+        ..err
+    })
+}
+
+// Resolve the jump targets of every loop in an already balanced program
+pub fn link(program: &mut [Inst]) {
+    use Inst::*;
+
+    let mut loops = vec![];
+    for index in 0..program.len() {
+        match program[index] {
+            LoopStart(_) => loops.push(index),
+            LoopEnd(_) => if let Some(start) = loops.pop() {
+                program[start] = LoopStart(index);
+                program[index] = LoopEnd(start);
+            },
+            _ => {}
+        }
+    }
+}
+
+// Check that every loop instruction points at its matching partner
+pub fn validate(program: &[Inst]) -> Result<(), CompileError> {
+    use Inst::*;
+
+    let mismatched = |index| Err(CompileError::new(CompileErrorKind::MismatchedJump(index), 0, 0));
+
+    let mut loops = vec![];
+    for (index, inst) in program.iter().enumerate() {
+        match *inst {
+            LoopStart(end) => match program.get(end) {
+                Some(&LoopEnd(start)) if start == index => loops.push(index),
+                _ => return mismatched(index)
+            },
+            LoopEnd(start) => match loops.pop() {
+                Some(top) if top == start => {},
+                _ => return mismatched(index)
+            },
+            _ => {}
+        }
+    }
+
+    match loops.pop() {
+        Some(index) => mismatched(index),
+        None => Ok(())
+    }
+}
+
+// The signed amount of an arithmetic or pointer instruction, along with
+// whether it moves the pointer
+fn delta(inst: &Inst) -> Option<(bool, isize)> {
+    use Inst::*;
+
+    match *inst {
+        Inc(amount) => Some((false, amount as isize)),
+        Dec(amount) => Some((false, -(amount as isize))),
+        ShiftRight(amount) => Some((true, amount as isize)),
+        ShiftLeft(amount) => Some((true, -(amount as isize))),
+        _ => None
+    }
+}
+
+// Merge adjacent instructions which partly or fully cancel out, like '+++--'
+// or '><', into a single instruction of the net amount
+fn simplify(program: Program) -> Program {
+    use Inst::*;
+
+    let mut output = Program::default();
+    for (index, inst) in program.code.iter().enumerate() {
+        let position = program.position(index);
+        let last = output.code.last().and_then(delta);
+        match (last, delta(inst)) {
+            (Some((moves, a)), Some((same, b))) if moves == same => {
+                output.code.pop();
+                let first = output.positions.pop().unwrap_or(position);
+
+                // What is left comes from the later one if it turned back,
+                // which is where a move off the tape would have been
+                let net = a + b;
+                let position = if (net > 0) == (a > 0) { first } else { position };
+                let inst = match (moves, net) {
+                    (_, 0) => continue,
+                    (false, _) if net > 0 => Inc(net as usize),
+                    (false, _) => Dec(-net as usize),
+                    (true, _) if net > 0 => ShiftRight(net as usize),
+                    (true, _) => ShiftLeft(-net as usize)
+                };
+
+                output.code.push(inst);
+                output.positions.push(position);
+            },
+            _ => {
+                output.code.push(inst.clone());
+                output.positions.push(position);
+            }
+        }
+    }
+
+    link(&mut output.code);
+    output
+}
+
+// Recognize a loop body which adds the current cell, multiplied by constant
+// factors, to its neighbours. The body may only do arithmetic, must return
+// the pointer to where it started and must decrement the current cell by one
+fn mul_add(body: &[Inst]) -> Option<Vec<Inst>> {
+    use Inst::*;
+
+    let mut offset: isize = 0;
+    let mut deltas: Vec<(isize, usize)> = vec![];
+
+    for inst in body {
+        let amount = match *inst {
+            Inc(amount) => amount,
+            Dec(amount) => amount.wrapping_neg(),
+            ShiftRight(amount) => {
+                offset += amount as isize;
+                continue;
+            },
+            ShiftLeft(amount) => {
+                offset -= amount as isize;
+                continue;
+            },
+            _ => return None
+        };
+
+        match deltas.iter_mut().find(|delta| delta.0 == offset) {
+            Some(delta) => delta.1 = delta.1.wrapping_add(amount),
+            None => deltas.push((offset, amount))
+        }
+    }
+
+    if offset != 0 || !deltas.contains(&(0, usize::MAX)) {
+        return None;
+    }
+
+    let mut output: Vec<Inst> = deltas.into_iter()
+        .filter(|&(offset, factor)| offset != 0 && factor != 0)
+        .map(|(offset, factor)| MulAdd { offset, factor })
+        .collect();
+
+    output.push(SetZero);
+    Some(output)
+}
+
+// Recognize a move to a neighbour, an addition to it and a move back
+fn add_at(code: &[Inst]) -> Option<Inst> {
+    use Inst::*;
+
+    let (offset, inst, back) = match *code {
+        [ShiftRight(there), ref inst, ShiftLeft(back), ..] => (there as isize, inst, back as isize),
+        [ShiftLeft(there), ref inst, ShiftRight(back), ..] => (-(there as isize), inst, -(back as isize)),
+        _ => return None
+    };
+
+    let amount = match *inst {
+        Inc(amount) if offset == back => amount,
+        Dec(amount) if offset == back => amount.wrapping_neg(),
+        _ => return None
+    };
+
+    Some(AddAt { offset, amount })
+}
+
+// Recognize a straight run of additions and moves starting at 'start',
+// collecting the net change to every cell it touches and the net move of the
+// pointer. Returns the block along with the number of instructions it replaces
+fn block(program: &Program, start: usize) -> Option<(Inst, usize)> {
+    use Inst::*;
+
+    let mut offset: isize = 0;
+    let mut deltas: Vec<(isize, usize)> = vec![];
+    let mut positions = vec![];
+    let mut moved = program.position(start);
+    let mut length = 0;
+
+    for (index, inst) in program.code.iter().enumerate().skip(start) {
+        let (target, amount, reached) = match *inst {
+            Inc(amount) => (offset, amount, moved),
+            Dec(amount) => (offset, amount.wrapping_neg(), moved),
+            AddAt { offset: target, amount } => (offset + target, amount, program.position(index)),
+            ShiftRight(amount) => {
+                offset += amount as isize;
+                moved = program.position(index);
+                length += 1;
+                continue;
+            },
+            ShiftLeft(amount) => {
+                offset -= amount as isize;
+                moved = program.position(index);
+                length += 1;
+                continue;
+            },
+            _ => break
+        };
+
+        match deltas.iter_mut().find(|delta| delta.0 == target) {
+            Some(delta) => delta.1 = delta.1.wrapping_add(amount),
+            None => {
+                deltas.push((target, amount));
+                positions.push(reached);
+            }
+        }
+        length += 1;
+    }
+
+    if length < 2 {
+        return None;
+    }
+
+    let (deltas, mut positions): (Vec<_>, Vec<_>) = deltas.into_iter()
+        .zip(positions)
+        .filter(|&((_, amount), _)| amount != 0)
+        .unzip();
+
+    positions.push(moved);
+    Some((Block { deltas, shift: offset, positions }, length))
+}
+
+// Collapse every straight run of additions and moves into a single block
+fn blocks(program: Program) -> Program {
+    let code = &program.code;
+    let mut output = Program::default();
+    let mut index = 0;
+
+    while index < code.len() {
+        let (inst, length) = block(&program, index).unwrap_or_else(|| (code[index].clone(), 1));
+        output.code.push(inst);
+        output.positions.push(program.position(index));
+        index += length;
+    }
+
+    link(&mut output.code);
+    output
+}
+
+// Collapse runs of clears one cell apart into a single clear of the range
+fn clears(program: Program) -> Program {
+    use Inst::*;
+
+    let code = &program.code;
+    let mut output = Program::default();
+    let mut index = 0;
+
+    while index < code.len() {
+        let mut len = 0;
+        if let SetZero = code[index] {
+            len = 1;
+            while let [ShiftRight(1), SetZero, ..] = code[index + len * 2 - 1..] {
+                len += 1;
+            }
+        }
+
+        output.positions.push(program.position(index));
+        if len > 1 {
+            output.code.push(ClearRange { len });
+            index += len * 2 - 1;
+        } else {
+            output.code.push(code[index].clone());
+            index += 1;
+        }
+    }
+
+    link(&mut output.code);
+    output
+}
+
+// Replace clear loops like '[-]' and '[+]' and scan loops like '[>]' with a
+// single instruction, multiply loops like '[->++<]' with additions to the
+// target cells, and additions to a neighbour like '>+<' with an offset write.
+// Neighbouring clears become a single clear of the range, and whatever
+// straight runs of additions and moves are left become blocks
+pub fn optimize(program: Program) -> Program {
+    use Inst::*;
+
+    let program = simplify(program);
+    let code = &program.code;
+    let mut output = Program::default();
+    let mut index = 0;
+
+    while index < code.len() {
+        let position = program.position(index);
+        let (insts, length) = match code[index..] {
+            [LoopStart(_), Inc(1), LoopEnd(_), ..] |
+            [LoopStart(_), Dec(1), LoopEnd(_), ..] => (vec![SetZero], 3),
+            [LoopStart(_), ShiftRight(1), LoopEnd(_), ..] => (vec![ScanRight], 3),
+            [LoopStart(_), ShiftLeft(1), LoopEnd(_), ..] => (vec![ScanLeft], 3),
+            [LoopStart(end), ..] if end > index => {
+                match code.get(index + 1..end).and_then(mul_add) {
+                    Some(insts) => (insts, end + 1 - index),
+                    None => (vec![code[index].clone()], 1)
+                }
+            },
+            _ => match add_at(&code[index..]) {
+                Some(inst) => (vec![inst], 3),
+                None => (vec![code[index].clone()], 1)
+            }
+        };
+
+        output.positions.extend(insts.iter().map(|_| position));
+        output.code.extend(insts);
+        index += length;
+    }
+
+    link(&mut output.code);
+    blocks(clears(propagate(output)))
+}
+
+// Follow the current cell while it holds a known constant, folding clears
+// and the additions after them into a single store and dropping clears and
+// loops which can never do anything. The cell is only known after a clear
+// or a loop, since a program may start on a tape which is not blank
+fn propagate(program: Program) -> Program {
+    use Inst::*;
+
+    let code = &program.code;
+    let mut output = Program::default();
+    let mut known = None;
+    let mut index = 0;
+
+    while index < code.len() {
+        let inst = &code[index];
+        let last = output.code.last();
+
+        let folded = match (last, inst) {
+            (_, SetZero) if known == Some(0) => None,
+            (Some(SetZero), &Inc(amount)) => Some(SetConst(amount)),
+            (Some(SetZero), &Dec(amount)) => Some(SetConst(amount.wrapping_neg())),
+            (Some(&SetConst(value)), &Inc(amount)) => Some(SetConst(value.wrapping_add(amount))),
+            (Some(&SetConst(value)), &Dec(amount)) => Some(SetConst(value.wrapping_sub(amount))),
+            (Some(SetConst(_)), SetZero) => Some(SetZero),
+
+            // A loop entered on a zero cell is skipped, along with its body
+            (_, &LoopStart(end)) if known == Some(0) && end > index => {
+                index = end + 1;
+                continue;
+            },
+
+            _ => {
+                output.code.push(inst.clone());
+                output.positions.push(program.position(index));
+                None
+            }
+        };
+
+        if let Some(folded) = folded {
+            output.code.pop();
+            output.code.push(folded);
+        }
+
+        known = match *output.code.last().unwrap_or(inst) {
+            SetZero | LoopEnd(_) => Some(0),
+            SetConst(value) => Some(value),
+            Output(_) | MulAdd { .. } | AddAt { .. } | Debug | PrintPointer => known,
+            _ => None
+        };
+        index += 1;
+    }
+
+    link(&mut output.code);
+    output
+}
+
+// The number of times a loop body runs when its control cell starts at
+// 'value', if the body decrements the control cell by a constant amount and
+// nothing else in it can change that cell
+fn iterations(body: &[Inst], value: isize) -> Option<usize> {
+    use Inst::*;
+
+    let mut offset: isize = 0;
+    let mut change: isize = 0;
+
+    for inst in body {
+        match *inst {
+            Inc(amount) if offset == 0 => change += amount as isize,
+            Dec(amount) if offset == 0 => change -= amount as isize,
+            ShiftRight(amount) => offset += amount as isize,
+            ShiftLeft(amount) => offset -= amount as isize,
+            Inc(_) | Dec(_) | Output(_) | Debug | PrintPointer => {},
+            Input(_) | SetZero | SetConst(_) if offset != 0 => {},
+            MulAdd { offset: target, .. } | AddAt { offset: target, .. }
+                if offset + target != 0 => {},
+            Block { ref deltas, shift, .. } => {
+                for &(target, amount) in deltas {
+                    if offset + target == 0 {
+                        change += amount as isize;
+                    }
+                }
+                offset += shift;
+            },
+            _ => return None
+        }
+    }
+
+    if offset == 0 && change < 0 && value % change == 0 {
+        Some((value / -change) as usize)
+    } else {
+        None
+    }
+}
+
+// Repeat the body of loops which run a statically known number of times, at
+// most 'limit' times, in place of the loop. The value of the current cell is
+// only known after it was cleared or a loop ended, since a program may start
+// on a tape which is not blank
+pub fn unroll(program: Program, limit: usize) -> Program {
+    use Inst::*;
+
+    let code = &program.code;
+    let mut output = Program::default();
+    let mut known: Option<isize> = None;
+    let mut index = 0;
+
+    while index < code.len() {
+        let inst = &code[index];
+        let position = program.position(index);
+
+        if let LoopStart(end) = *inst {
+            let count = known
+                .filter(|&value| value > 0 && value <= u8::MAX as isize && end > index)
+                .and_then(|value| iterations(&code[index + 1..end], value))
+                .filter(|&count| count <= limit);
+
+            if let Some(count) = count {
+                for _ in 0..count {
+                    output.code.extend_from_slice(&code[index + 1..end]);
+                    output.positions.extend((index + 1..end).map(|index| program.position(index)));
+                }
+
+                known = Some(0);
+                index = end + 1;
+                continue;
+            }
+        }
+
+        known = match *inst {
+            Inc(amount) => known.map(|value| value + amount as isize),
+            Dec(amount) => known.map(|value| value - amount as isize),
+            SetZero | ClearRange { .. } | LoopEnd(_) => Some(0),
+            SetConst(value) => Some(value as isize),
+            Output(_) | MulAdd { .. } | AddAt { .. } | Debug | PrintPointer => known,
+            _ => None
+        };
+
+        output.code.push(inst.clone());
+        output.positions.push(position);
+        index += 1;
+    }
+
+    link(&mut output.code);
+    output
+}
+
+// The kinds of suspicious code found by 'analyze'
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WarningKind {
+    // A loop whose body only ever increases its control cell
+    IncreasingLoop,
+
+    // A loop with nothing in it, which never ends once entered
+    EmptyLoop,
+
+    // A single run of '>' or '<' longer than the whole tape
+    LongShift { amount: usize, tape_size: usize },
+
+    // Code after an empty loop entered on a cell known to be nonzero, up to
+    // the line and column of the last instruction of the program
+    Unreachable { end: (usize, usize) },
+}
+
+// Suspicious code which is still allowed to compile and run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub line: usize,
+    pub column: usize,
+    pub kind: WarningKind,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: warning: ", self.line, self.column)?;
+        match self.kind {
+            WarningKind::IncreasingLoop =>
+                write!(f, "loop increases its control cell and may never end"),
+            WarningKind::EmptyLoop =>
+                write!(f, "empty loop never ends if the cell is nonzero"),
+            WarningKind::LongShift { amount, tape_size } =>
+                write!(f, "moving the pointer by {} is more than the {} cells of the tape",
+                       amount, tape_size),
+            WarningKind::Unreachable { end: (line, column) } =>
+                write!(f, "code from here to {}:{} is never run, since the loop before it never ends",
+                       line, column),
+        }
+    }
+}
+
+// The net change a loop body makes to its control cell, if the body only
+// does arithmetic and returns the pointer to where it started
+fn control_delta(body: &[Inst]) -> Option<isize> {
+    let mut offset = 0;
+    let mut change = 0;
+
+    for inst in body {
+        match delta(inst)? {
+            (true, amount) => offset += amount,
+            (false, amount) if offset == 0 => change += amount,
+            _ => {}
+        }
+    }
+
+    if offset == 0 {
+        Some(change)
+    } else {
+        None
+    }
+}
+
+// Look for code in an unoptimized program which is likely a mistake, when run
+// on a tape of 'tape_size' cells. The program may only be assumed to start on
+// a zero cell if the tape is 'blank', rather than left by an earlier program
+pub fn analyze(program: &Program, tape_size: usize, blank: bool) -> Vec<Warning> {
+    use Inst::*;
+
+    // The value of the current cell while it is known, from the start of a
+    // program on a blank tape or the end of a loop up to the next move or
+    // input. Values which are a multiple of 256 might be zero in a byte cell,
+    // so they are never taken to be nonzero
+    let mut known: Option<isize> = if blank { Some(0) } else { None };
+    let mut unreachable = false;
+
+    let mut warnings = vec![];
+    for (index, inst) in program.code.iter().enumerate() {
+        let nonzero = matches!(known, Some(value) if value % 256 != 0);
+        known = match *inst {
+            Inc(amount) => known.map(|value| value.wrapping_add(amount as isize)),
+            Dec(amount) => known.map(|value| value.wrapping_sub(amount as isize)),
+            LoopEnd(_) => Some(0),
+            Output(_) | Debug | PrintPointer => known,
+            _ => None
+        };
+
+        if let ShiftRight(amount) | ShiftLeft(amount) = *inst {
+            if amount > tape_size {
+                let (line, column) = program.position(index);
+                warnings.push(Warning { line, column, kind: WarningKind::LongShift { amount, tape_size } });
+            }
+        }
+
+        let end = match *inst {
+            LoopStart(end) if end > index => end,
+            _ => continue
+        };
+
+        let body = &program.code[index + 1..end];
+        let (line, column) = program.position(index);
+
+        // Sometimes written on purpose, to halt if the cell is nonzero
+        if body.is_empty() {
+            warnings.push(Warning { line, column, kind: WarningKind::EmptyLoop });
+
+            // Nothing after a loop which is known to never end is ever run
+            if nonzero && !unreachable && end + 1 < program.code.len() {
+                let (line, column) = program.position(end + 1);
+                let end = program.position(program.code.len() - 1);
+                warnings.push(Warning { line, column, kind: WarningKind::Unreachable { end } });
+                unreachable = true;
+            }
+            continue;
+        }
+
+        // A lone '+' wraps around to zero, making '[+]' a clear loop
+        if let [Inc(1)] = *body {
+            continue;
+        }
+
+        if matches!(control_delta(body), Some(change) if change > 0) {
+            warnings.push(Warning { line, column, kind: WarningKind::IncreasingLoop });
+        }
+    }
+
+    warnings
+}
+
+// The default length of the tape
+pub const TAPE_LENGTH: usize = 30000;
+
+// The default length a growing tape is capped at
+pub const MAX_TAPE_LENGTH: usize = 1 << 26;
+
+// The parameters of the 64-bit FNV-1a hash of the output
+#[cfg(not(no_std))]
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+#[cfg(not(no_std))]
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// The start of every state written by 'save_state'
+#[cfg(not(no_std))]
+const STATE_MAGIC: &[u8] = b"BFS\x01";
+
+// The default size of the output buffer, the same as the standard library's
+pub const OUTPUT_BUFFER_SIZE: usize = 8 * 1024;
+
+// What an input instruction stores in the cell once the input is exhausted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eof {
+    Zero,
+    NegOne,
+    Unchanged,
+}
+
+// What happens when the memory pointer moves past either end of the tape
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bounds {
+    Wrap,
+    Error,
+
+    // Extend the tape with zeros when moving past its right end
+    Grow,
+}
+
+// The runtime options of the virtual machine
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub tape_size: usize,
+    pub max_tape_size: usize,
+    pub bounds: Bounds,
+    pub eof: Eof,
+    pub numeric_output: bool,
+
+    // Read each input cell as a decimal number, separated from the next by
+    // whitespace. Anything else which is not a number counts as the end of
+    // the input
+    pub numeric_input: bool,
+
+    // Write each output cell as its code and character, like "[65 0x41 'A']"
+    pub annotate_output: bool,
+    pub latin1: bool,
+    pub crlf: bool,
+
+    // Stop cell arithmetic at the smallest and largest values of the cell
+    // instead of wrapping around. Only unoptimized programs behave the same
+    // way, since the optimizer assumes that cells wrap
+    pub saturate: bool,
+
+    // Write every byte read to the output as well, so transcripts of piped
+    // input show it alongside the output. The bytes count towards the output
+    // limit and the digest like any other output
+    pub echo_input: bool,
+    pub profile: bool,
+
+    // Count how many times each loop jumps back to its start
+    pub loop_profile: bool,
+
+    // Log every executed instruction to standard error. This is extremely
+    // verbose and only meant for short programs
+    pub trace: bool,
+    pub max_steps: Option<u64>,
+    pub max_output: Option<u64>,
+
+    // The bytes of output held back before writing them out. The buffer is
+    // still flushed before every input, so a prompt is always visible while
+    // the program waits for an answer, and once the program finishes
+    pub output_buffer_size: usize,
+
+    // Keep an FNV-1a hash of every byte written to the output
+    pub digest: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tape_size: TAPE_LENGTH,
+            max_tape_size: MAX_TAPE_LENGTH,
+            bounds: Bounds::Wrap,
+            eof: Eof::Zero,
+            numeric_output: false,
+            numeric_input: false,
+            annotate_output: false,
+            latin1: false,
+            crlf: false,
+            saturate: false,
+            echo_input: false,
+            profile: false,
+            loop_profile: false,
+            trace: false,
+            max_steps: None,
+            max_output: None,
+            output_buffer_size: OUTPUT_BUFFER_SIZE,
+            digest: false
+        }
+    }
+}
+
+// A single cell of the tape, wrapping at the bounds of its width
+pub trait Cell: Copy + PartialEq + fmt::Display + fmt::LowerHex + fmt::UpperHex {
+    const ZERO: Self;
+
+    fn add(self, amount: usize) -> Self;
+    fn sub(self, amount: usize) -> Self;
+    fn saturating_add(self, amount: usize) -> Self;
+    fn saturating_sub(self, amount: usize) -> Self;
+    fn from_byte(byte: u8) -> Self;
+    fn low_byte(self) -> u8;
+    fn to_usize(self) -> usize;
+}
+
+// Implement the cell trait for the integer types. Signed cells wrap within
+// their own range and are output as the same bits reinterpreted as unsigned
+macro_rules! impl_cell {
+    ($($type: tt),*) => {$(
+        impl Cell for $type {
+            const ZERO: Self = 0;
+
+            fn add(self, amount: usize) -> Self {
+                self.wrapping_add(amount as $type)
+            }
+
+            fn sub(self, amount: usize) -> Self {
+                self.wrapping_sub(amount as $type)
+            }
+
+            fn saturating_add(self, amount: usize) -> Self {
+                $type::try_from(amount).map_or($type::MAX, |amount| self.saturating_add(amount))
+            }
+
+            fn saturating_sub(self, amount: usize) -> Self {
+                $type::try_from(amount).map_or($type::MIN, |amount| self.saturating_sub(amount))
+            }
+
+            fn from_byte(byte: u8) -> Self {
+                byte as $type
+            }
+
+            fn low_byte(self) -> u8 {
+                self as u8
+            }
+
+            fn to_usize(self) -> usize {
+                self as usize
+            }
+        }
+    )*};
+}
+
+impl_cell!(u8, u16, u32, i8, i16, i32);
+
+// Where a machine reads the input of a program from, one byte at a time,
+// with nothing left once the input has ended
+pub trait ReadByte {
+    fn read_byte(&mut self) -> Option<u8>;
+}
+
+// Where a machine writes the output of a program to, one byte at a time
+pub trait WriteByte {
+    fn write_byte(&mut self, byte: u8);
+}
+
+// A slice is read from the front, leaving the rest of it to read next
+impl ReadByte for &[u8] {
+    fn read_byte(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.split_first()?;
+        *self = rest;
+        Some(byte)
+    }
+}
+
+impl WriteByte for Vec<u8> {
+    fn write_byte(&mut self, byte: u8) {
+        self.push(byte);
+    }
+}
+
+impl<W: WriteByte + ?Sized> WriteByte for &mut W {
+    fn write_byte(&mut self, byte: u8) {
+        (**self).write_byte(byte);
+    }
+}
+
+// The ways a program running on a machine can stop before its end
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    OutOfBounds { ip: usize, line: usize, column: usize },
+    StepLimit { ip: usize, mp: usize },
+
+    // The '@' extension needs the second tape of a 'Vm'
+    DualTape { ip: usize },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::OutOfBounds { ip, line, column } =>
+                write!(f, "{}:{}: memory pointer moved outside of the tape (ip = {})", line, column, ip),
+            Fault::StepLimit { ip, mp } =>
+                write!(f, "step limit exceeded at ip = {}, mp = {}", ip, mp),
+            Fault::DualTape { ip } =>
+                write!(f, "there is no other tape to switch to at ip = {}", ip),
+        }
+    }
+}
+
+// What the core of a machine leaves to the machine running it, which is the
+// input and output of cells and the extensions. A 'Machine' passes the low
+// bytes of the cells on as they are and has nowhere to run the extensions,
+// while a 'Vm' formats and counts the cells, stops at the errors of its
+// streams and keeps the other tape of '@'
+trait Host<T: Cell, S> {
+    type Error;
+
+    // The error for the instruction at 'ip' moving the pointer off the tape,
+    // blaming the command on 'line' and 'column'
+    fn out_of_bounds(ip: usize, line: usize, column: usize) -> Self::Error;
+
+    // Write 'count' copies of a cell for the output instruction at 'ip'
+    fn write(&mut self, ip: usize, cell: T, count: usize) -> Result<(), Self::Error>;
+
+    // Get ready for an input instruction reading 'count' cells
+    fn prepare(&mut self, _count: usize) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    // Read the next cell for the input instruction at 'ip', with nothing
+    // left once the input has ended
+    fn read(&mut self, ip: usize) -> Result<Option<T>, Self::Error>;
+
+    // Run the extension 'inst' at 'ip' on the active tape, which '@' swaps
+    // for the other one along with its pointer and highest visited cell
+    fn extend(&mut self,
+              ip: usize,
+              inst: &Inst,
+              tape: &mut S,
+              mp: &mut usize,
+              touched: &mut usize) -> Result<(), Self::Error>;
+}
+
+// A tape which may be extended to 'length' cells, which only a tape the
+// machine owns can be
+trait Grow {
+    fn grow(&mut self, length: usize) -> bool;
+}
+
+impl<T: Cell> Grow for Vec<T> {
+    fn grow(&mut self, length: usize) -> bool {
+        self.resize(length, T::ZERO);
+        true
+    }
+}
+
+impl<T> Grow for &mut [T] {
+    fn grow(&mut self, _length: usize) -> bool {
+        false
+    }
+}
+
+// The state of a running program, and the instructions which 'Machine' and
+// 'Vm' both execute. The extensions are left to its host
+struct Core<S, H> {
+    tape: S,
+    mp: usize,
+    ip: usize,
+    touched: usize,
+    max_tape_size: usize,
+    bounds: Bounds,
+    eof: Eof,
+    saturate: bool,
+    host: H,
+}
+
+impl<T: Cell, S: DerefMut<Target = [T]> + Grow, H: Host<T, S>> Core<S, H> {
+    fn new(tape: S, mp: usize, config: &Config, host: H) -> Self {
+        let touched = tape.iter().rposition(|&cell| cell != T::ZERO).unwrap_or(0).max(mp);
+
+        Self {
+            tape,
+            mp,
+            ip: 0,
+            touched,
+            max_tape_size: config.max_tape_size,
+            bounds: config.bounds,
+            eof: config.eof,
+            saturate: config.saturate,
+            host
+        }
+    }
+
+    // The error for the current instruction moving the pointer off the tape,
+    // or for the command of a block at 'position'
+    fn out_of_bounds(&self, program: &Program, position: Option<(usize, usize)>) -> H::Error {
+        let (line, column) = position.unwrap_or_else(|| program.position(self.ip));
+        H::out_of_bounds(self.ip, line, column)
+    }
+
+    // Resolve the cell 'offset' away from the pointer according to the bounds
+    // of the tape, growing the tape if needed and it can. The pointer is only
+    // ever moved by the distance, so no amount of shifting overflows on the
+    // way there
+    fn address(&mut self, offset: isize) -> Option<usize> {
+        let length = self.tape.len();
+        let distance = offset.unsigned_abs();
+        let target = if offset < 0 {
+            self.mp.checked_sub(distance)
+        } else {
+            self.mp.checked_add(distance)
+        };
+
+        match (target, self.bounds) {
+            (Some(target), _) if target < length => Some(target),
+
+            // The pointer is on the tape, so moving less than its length in
+            // either direction never passes zero or twice its length
+            (_, Bounds::Wrap) if offset < 0 => Some((self.mp + length - distance % length) % length),
+            (_, Bounds::Wrap) => Some((self.mp + distance % length) % length),
+
+            (Some(target), Bounds::Grow) if offset > 0 && target < self.max_tape_size => {
+                let length = length.saturating_mul(2).max(target + 1).min(self.max_tape_size);
+                self.tape.grow(length).then_some(target)
+            },
+            _ => None
+        }
+    }
+
+    // Resolve a cell like 'address', blaming the command at 'position', or
+    // else the current instruction, if it is off the tape
+    fn reach(&mut self,
+             program: &Program,
+             offset: isize,
+             position: Option<(usize, usize)>) -> Result<usize, H::Error> {
+        match self.address(offset) {
+            Some(target) => Ok(target),
+            None => Err(self.out_of_bounds(program, position))
+        }
+    }
+
+    // Execute the current instruction, returning the index of the next one.
+    // A loop jumps straight past its partner, so '[' is not checked again
+    // after every iteration. It is inlined into the loops running it, which
+    // otherwise spend most of their time passing its result back
+    #[inline(always)]
+    fn execute(&mut self, program: &Program) -> Result<usize, H::Error> {
+        use Inst::*;
+
+        match program.code[self.ip] {
+            Inc(amount) if self.saturate =>
+                self.tape[self.mp] = self.tape[self.mp].saturating_add(amount),
+            Dec(amount) if self.saturate =>
+                self.tape[self.mp] = self.tape[self.mp].saturating_sub(amount),
+            Inc(amount) => self.tape[self.mp] = self.tape[self.mp].add(amount),
+            Dec(amount) => self.tape[self.mp] = self.tape[self.mp].sub(amount),
+
+            // Nearly every move stays on the tape, which needs no bounds
+            ShiftRight(amount) if amount < self.tape.len() - self.mp => {
+                self.mp += amount;
+                self.touched = self.touched.max(self.mp);
+            },
+            ShiftLeft(amount) if amount <= self.mp => self.mp -= amount,
+
+            ShiftRight(amount) => {
+                self.mp = self.reach(program, amount as isize, None)?;
+                self.touched = self.touched.max(self.mp);
+            },
+
+            ShiftLeft(amount) => {
+                self.mp = self.reach(program, -(amount as isize), None)?;
+                self.touched = self.touched.max(self.mp);
+            },
+
+            Output(amount) => self.host.write(self.ip, self.tape[self.mp], amount)?,
+
+            Input(amount) => {
+                self.host.prepare(amount)?;
+                for _ in 0..amount {
+                    match (self.host.read(self.ip)?, self.eof) {
+                        (Some(cell), _) => self.tape[self.mp] = cell,
+                        (None, Eof::Zero) => self.tape[self.mp] = T::ZERO,
+                        (None, Eof::NegOne) => self.tape[self.mp] = T::ZERO.sub(1),
+                        (None, Eof::Unchanged) => {}
+                    }
+                }
+            },
+
+            LoopStart(i) => {
+                debug_assert!(i < program.code.len(), "jump target {} out of range", i);
+                if self.tape[self.mp] == T::ZERO {
+                    return Ok(i + 1);
+                }
+            },
+
+            LoopEnd(i) => {
+                debug_assert!(i < program.code.len(), "jump target {} out of range", i);
+                if self.tape[self.mp] != T::ZERO {
+                    return Ok(i + 1);
+                }
+            },
+
+            SetZero => self.tape[self.mp] = T::ZERO,
+            SetConst(value) => self.tape[self.mp] = T::ZERO.add(value),
+
+            // The loop this came from never runs on a zero cell, so it must
+            // not move off the tape or grow it either
+            MulAdd { .. } if self.tape[self.mp] == T::ZERO => {},
+            MulAdd { offset, factor } => {
+                let target = self.reach(program, offset, None)?;
+                let amount = self.tape[self.mp].to_usize().wrapping_mul(factor);
+                self.tape[target] = self.tape[target].add(amount);
+                self.touched = self.touched.max(target);
+            },
+
+            AddAt { offset, amount } => {
+                let target = self.reach(program, offset, None)?;
+                self.tape[target] = self.tape[target].add(amount);
+                self.touched = self.touched.max(target);
+            },
+
+            Block { ref deltas, shift, ref positions } => {
+                // Only cells past either end of the tape need to go through
+                // its bounds
+                let base = self.mp as isize;
+                let length = self.tape.len() as isize;
+                let inside = |offset| matches!(base.checked_add(offset),
+                                               Some(target) if (0..length).contains(&target));
+                if deltas.iter().all(|&(offset, _)| inside(offset)) {
+                    for &(offset, amount) in deltas {
+                        let target = (base + offset) as usize;
+                        self.tape[target] = self.tape[target].add(amount);
+                        self.touched = self.touched.max(target);
+                    }
+                } else {
+                    for (index, &(offset, amount)) in deltas.iter().enumerate() {
+                        let target = self.reach(program, offset, positions.get(index).copied())?;
+                        self.tape[target] = self.tape[target].add(amount);
+                        self.touched = self.touched.max(target);
+                    }
+                }
+
+                self.mp = if inside(shift) {
+                    (base + shift) as usize
+                } else {
+                    self.reach(program, shift, positions.last().copied())?
+                };
+                self.touched = self.touched.max(self.mp);
+            },
+
+            ClearRange { len } => {
+                // Only a range crossing the end of the tape needs to go
+                // through its bounds one cell at a time
+                if len <= self.tape.len() - self.mp {
+                    self.tape[self.mp..self.mp + len].fill(T::ZERO);
+                    self.mp += len - 1;
+                } else {
+                    self.tape[self.mp] = T::ZERO;
+                    for _ in 1..len {
+                        self.mp = self.reach(program, 1, None)?;
+                        self.tape[self.mp] = T::ZERO;
+                    }
+                }
+                self.touched = self.touched.max(self.mp);
+            },
+
+            ScanRight => {
+                let zero = self.tape[self.mp..].iter().position(|&cell| cell == T::ZERO);
+                match (zero, self.bounds) {
+                    (Some(offset), _) => self.mp += offset,
+                    (None, Bounds::Wrap) => match self.tape.iter().position(|&cell| cell == T::ZERO) {
+                        Some(index) => self.mp = index,
+                        // Every cell is nonzero, so the scan never ends
+                        None => return Ok(self.ip)
+                    },
+                    (None, _) => {
+                        let offset = self.tape.len() - self.mp;
+                        self.mp = self.reach(program, offset as isize, None)?;
+                    }
+                }
+                self.touched = self.touched.max(self.mp);
+            },
+
+            ScanLeft => {
+                let zero = self.tape[..=self.mp].iter().rposition(|&cell| cell == T::ZERO);
+                match (zero, self.bounds) {
+                    (Some(index), _) => self.mp = index,
+                    (None, Bounds::Wrap) => match self.tape.iter().rposition(|&cell| cell == T::ZERO) {
+                        Some(index) => self.mp = index,
+                        // Every cell is nonzero, so the scan never ends
+                        None => return Ok(self.ip)
+                    },
+                    (None, _) => return Err(self.out_of_bounds(program, None))
+                }
+                self.touched = self.touched.max(self.mp);
+            },
+
+            Debug | PrintPointer | SwapTape =>
+                self.host.extend(self.ip, &program.code[self.ip], &mut self.tape,
+                                 &mut self.mp, &mut self.touched)?
+        }
+
+        Ok(self.ip + 1)
+    }
+}
+
+// The host of a 'Machine', reading and writing the low byte of every cell
+struct Bytes<I, O> {
+    input: I,
+    output: O,
+}
+
+impl<T: Cell, S, I: ReadByte, O: WriteByte> Host<T, S> for Bytes<I, O> {
+    type Error = Fault;
+
+    fn out_of_bounds(ip: usize, line: usize, column: usize) -> Fault {
+        Fault::OutOfBounds { ip, line, column }
+    }
+
+    fn write(&mut self, _ip: usize, cell: T, count: usize) -> Result<(), Fault> {
+        let byte = cell.low_byte();
+        for _ in 0..count {
+            self.output.write_byte(byte);
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, _ip: usize) -> Result<Option<T>, Fault> {
+        Ok(self.input.read_byte().map(T::from_byte))
+    }
+
+    // There is no other tape for '@' to switch to
+    fn extend(&mut self,
+              ip: usize,
+              inst: &Inst,
+              _tape: &mut S,
+              _mp: &mut usize,
+              _touched: &mut usize) -> Result<(), Fault> {
+        match *inst {
+            Inst::SwapTape => Err(Fault::DualTape { ip }),
+            _ => Ok(())
+        }
+    }
+}
+
+// A virtual machine which needs neither std nor an allocation of its own. It
+// runs a program on a tape of the caller's, which never grows, so a growing
+// tape stops at its end just like one with hard ends. Only the bounds, the
+// end of input, saturation and the step limit of the config apply to it, and
+// '#' and '$' do nothing since there is nowhere to write them
+pub struct Machine<'a, T: Cell, I: ReadByte, O: WriteByte> {
+    program: &'a Program,
+    core: Core<&'a mut [T], Bytes<I, O>>,
+    steps: u64,
+    max_steps: u64,
+}
+
+impl<'a, T: Cell, I: ReadByte, O: WriteByte> Machine<'a, T, I, O> {
+    // Start a program with the pointer at 'mp', which must be on the tape
+    pub fn new(program: &'a Program,
+               config: &Config,
+               tape: &'a mut [T],
+               mp: usize,
+               input: I,
+               output: O) -> Self {
+        assert!(mp < tape.len(), "the pointer must start on the tape");
+
+        Self {
+            program,
+            core: Core::new(tape, mp, config, Bytes { input, output }),
+            steps: 0,
+            max_steps: config.max_steps.unwrap_or(u64::MAX)
+        }
+    }
+
+    // Run the program until it finishes
+    pub fn run(&mut self) -> Result<(), Fault> {
+        while self.core.ip < self.program.code.len() {
+            if self.steps >= self.max_steps {
+                return Err(Fault::StepLimit { ip: self.core.ip, mp: self.core.mp });
+            }
+            self.steps += 1;
+            self.core.ip = self.core.execute(self.program)?;
+        }
+        Ok(())
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.core.mp
+    }
+
+    pub fn tape(&self) -> &[T] {
+        self.core.tape
+    }
+}
+
+// The host of a 'Vm', formatting and counting the cells it writes, stopping
+// at the errors of its streams and keeping the other tape of '@'
+#[cfg(not(no_std))]
+struct Streams<'a, T> {
+    // The inactive tape of the '@' extension, along with its own pointer and
+    // highest visited cell. It is only allocated once first switched to
+    other: Vec<T>,
+    other_mp: usize,
+    other_touched: usize,
+    active_tape: usize,
+
+    inputs: u64,
+    outputs: u64,
+    written: u64,
+    max_output: u64,
+    numeric_output: bool,
+    numeric_input: bool,
+    annotate_output: bool,
+    latin1: bool,
+    crlf: bool,
+    echo_input: bool,
+    digest: Option<u64>,
+    input: BufReader<Box<dyn Read + 'a>>,
+    output: BufWriter<Box<dyn Write + 'a>>
+}
+
+#[cfg(not(no_std))]
+impl<'a, T: Cell> Streams<'a, T> {
+    // Write 'count' copies of 'text' to the output, or only as many bytes of
+    // them as the output limit still allows, returning how many it wrote
+    #[inline]
+    fn write_copies(&mut self, text: &[u8], count: usize) -> io::Result<u64> {
+        let allowed = ((text.len() * count) as u64).min(self.max_output - self.written);
+        self.written += allowed;
+
+        if let Some(ref mut digest) = self.digest {
+            for &byte in text.iter().cycle().take(allowed as usize) {
+                *digest = (*digest ^ byte as u64).wrapping_mul(FNV_PRIME);
+            }
+        }
+
+        // A run of a single byte is written a whole chunk at a time
+        match *text {
+            [byte] if allowed == 1 => self.output.write_all(&[byte])?,
+            [byte] => {
+                let chunk = [byte; 256];
+                let mut left = allowed as usize;
+                while left > 0 {
+                    let length = left.min(chunk.len());
+                    self.output.write_all(&chunk[..length])?;
+                    left -= length;
+                }
+            },
+            _ => {
+                let copies = allowed as usize / text.len();
+                for _ in 0..copies {
+                    self.output.write_all(text)?;
+                }
+                self.output.write_all(&text[..allowed as usize % text.len()])?;
+            }
+        }
+
+        Ok(allowed)
+    }
+
+    // Write a byte read back to the output for the input instruction at
+    // 'ip', counted against the output limit like any other byte written
+    fn echo(&mut self, ip: usize, byte: u8) -> Result<(), RunError> {
+        if self.write_copies(&[byte], 1)? == 0 {
+            self.output.flush()?;
+            return Err(RunError::OutputLimit { ip, limit: self.max_output });
+        }
+        Ok(())
+    }
+
+    // Read the next whitespace separated decimal number of the input, wrapped
+    // to the size of a cell. There is none once the input ends, and neither
+    // is there if the next word is not a number
+    fn read_number(&mut self, ip: usize) -> Result<Option<T>, RunError> {
+        let mut value: usize = 0;
+        let mut digits = 0;
+        let mut negative = false;
+        let mut malformed = false;
+
+        while let Some(&byte) = self.input.fill_buf()?.first() {
+            self.input.consume(1);
+
+            if self.echo_input {
+                self.echo(ip, byte)?;
+            }
+
+            match byte {
+                _ if byte.is_ascii_whitespace() => if digits > 0 || negative || malformed {
+                    break;
+                },
+                b'-' if digits == 0 && !negative => negative = true,
+                b'0'..=b'9' => {
+                    value = value.wrapping_mul(10).wrapping_add((byte - b'0') as usize);
+                    digits += 1;
+                },
+                _ => malformed = true
+            }
+        }
+
+        if digits == 0 || malformed {
+            Ok(None)
+        } else if negative {
+            Ok(Some(T::ZERO.sub(value)))
+        } else {
+            Ok(Some(T::ZERO.add(value)))
+        }
+    }
+}
+
+#[cfg(not(no_std))]
+impl<'a, T: Cell> Host<T, Vec<T>> for Streams<'a, T> {
+    type Error = RunError;
+
+    fn out_of_bounds(ip: usize, line: usize, column: usize) -> RunError {
+        RunError::OutOfBounds { ip, line, column }
+    }
+
+    #[inline]
+    fn write(&mut self, ip: usize, cell: T, count: usize) -> Result<(), RunError> {
+        // Nearly every output is a single byte written as it is
+        if count == 1 && !(self.annotate_output || self.numeric_output || self.crlf || self.latin1) &&
+           self.digest.is_none() && self.written < self.max_output {
+            self.output.write_all(&[cell.low_byte()])?;
+            self.outputs += 1;
+            self.written += 1;
+            return Ok(());
+        }
+
+        // Every copy of the cell is written the same way, so it is only
+        // formatted once
+        let byte = cell.low_byte();
+        let plain = [byte];
+        let formatted;
+        let text: &[u8] = if self.annotate_output {
+            formatted = format!("[{} 0x{:02X} '{}']", cell, cell, (byte as char).escape_default());
+            formatted.as_bytes()
+        } else if self.numeric_output {
+            formatted = format!("{} ", cell);
+            formatted.as_bytes()
+        } else if self.crlf && byte == b'\n' {
+            b"\r\n"
+        } else if self.latin1 && byte >= 0x80 {
+            formatted = (byte as char).to_string();
+            formatted.as_bytes()
+        } else {
+            &plain
+        };
+
+        let written = self.write_copies(text, count)?;
+        if written == (text.len() * count) as u64 {
+            self.outputs += count as u64;
+            Ok(())
+        } else {
+            self.outputs += written.div_ceil(text.len() as u64);
+            self.output.flush()?;
+            Err(RunError::OutputLimit { ip, limit: self.max_output })
+        }
+    }
+
+    fn prepare(&mut self, count: usize) -> Result<(), RunError> {
+        // Make sure any prompt is visible before waiting for input
+        self.output.flush()?;
+        self.inputs += count as u64;
+        Ok(())
+    }
+
+    fn read(&mut self, ip: usize) -> Result<Option<T>, RunError> {
+        if self.numeric_input {
+            return self.read_number(ip);
+        }
+
+        let mut byte = [0];
+        if self.input.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+
+        if self.echo_input {
+            self.echo(ip, byte[0])?;
+        }
+        Ok(Some(T::from_byte(byte[0])))
+    }
+
+    fn extend(&mut self,
+              ip: usize,
+              inst: &Inst,
+              tape: &mut Vec<T>,
+              mp: &mut usize,
+              touched: &mut usize) -> Result<(), RunError> {
+        match *inst {
+            Inst::Debug => {
+                self.output.flush()?;
+                eprintln!("# ip = {} mp = {}:{}", ip, mp, nearby(tape, *mp));
+            },
+
+            Inst::PrintPointer => {
+                self.output.flush()?;
+                eprintln!("$ mp = {}", mp);
+            },
+
+            _ => {
+                if self.other.is_empty() {
+                    self.other = vec![T::ZERO; tape.len()];
+                }
+
+                mem::swap(tape, &mut self.other);
+                mem::swap(mp, &mut self.other_mp);
+                mem::swap(touched, &mut self.other_touched);
+                self.active_tape ^= 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The cells around the pointer, with the current one in brackets
+#[cfg(not(no_std))]
+fn nearby<T: Cell>(tape: &[T], mp: usize) -> String {
+    let start = mp.saturating_sub(4);
+    let end = (mp + 5).min(tape.len());
+
+    let mut cells = String::new();
+    for (address, cell) in tape[start..end].iter().enumerate() {
+        if start + address == mp {
+            cells += &format!(" [{}]", cell);
+        } else {
+            cells += &format!(" {}", cell);
+        }
+    }
+    cells
+}
+
+// The virtual machine where the program is executed
+#[cfg(not(no_std))]
+pub struct Vm<'a, T: Cell> {
+    core: Core<Vec<T>, Streams<'a, T>>,
+    program: Program,
+    counts: Vec<u64>,
+    profile: bool,
+    iterations: Vec<u64>,
+    loop_profile: bool,
+    steps: u64,
+    max_steps: u64,
+    trace: bool,
+    interrupt: Option<&'a AtomicBool>,
+}
+
+// Counters collected while running a program. The outputs are the cells
+// written, and the bytes they were written as after any formatting
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RunStats {
+    pub instructions: u64,
+    pub inputs: u64,
+    pub outputs: u64,
+    pub written: u64,
+    pub peak_mp: usize,
+}
+
+// The custom turing machine for the generated instructions
+#[cfg(not(no_std))]
+impl<'a, T: Cell> Vm<'a, T> {
+
+    // Create a virtual machine from a source program
+    pub fn new(program: Program,
+               config: &Config,
+               input: Box<dyn Read + 'a>,
+               output: Box<dyn Write + 'a>) -> Self {
+        Self::with_tape(program, config, vec![T::ZERO; config.tape_size], 0, input, output)
+    }
+
+    // Create a virtual machine which starts on a tape prepared beforehand,
+    // with the pointer at 'mp'. The tape keeps its own length instead of the
+    // one in the config, and is padded with zero cells if the pointer lies
+    // past its end. It still grows or wraps according to the bounds
+    pub fn with_tape(program: Program,
+                     config: &Config,
+                     tape: Vec<T>,
+                     mp: usize,
+                     input: Box<dyn Read + 'a>,
+                     output: Box<dyn Write + 'a>) -> Self {
+        let mut memory = tape;
+        if memory.len() <= mp {
+            memory.resize(mp + 1, T::ZERO);
+        }
+
+        let streams = Streams {
+            other: vec![],
+            other_mp: 0,
+            other_touched: 0,
+            active_tape: 0,
+            inputs: 0,
+            outputs: 0,
+            written: 0,
+            max_output: config.max_output.unwrap_or(u64::MAX),
+            numeric_output: config.numeric_output,
+            numeric_input: config.numeric_input,
+            annotate_output: config.annotate_output,
+            latin1: config.latin1,
+            crlf: config.crlf,
+            echo_input: config.echo_input,
+            digest: if config.digest { Some(FNV_OFFSET) } else { None },
+            input: BufReader::new(input),
+            output: BufWriter::with_capacity(config.output_buffer_size, output)
+        };
+
+        Self {
+            core: Core::new(memory, mp, config, streams),
+            counts: if config.profile { vec![0; program.code.len()] } else { vec![] },
+            profile: config.profile,
+            iterations: if config.loop_profile { vec![0; program.code.len()] } else { vec![] },
+            loop_profile: config.loop_profile,
+            program,
+            steps: 0,
+            max_steps: config.max_steps.unwrap_or(u64::MAX),
+            trace: config.trace,
+            interrupt: None
+        }
+    }
+
+    // Count the current instruction against the step limit and the profile,
+    // and trace it if asked to
+    fn account(&mut self) -> Result<(), RunError> {
+        if self.steps >= self.max_steps {
+            return Err(RunError::StepLimit { ip: self.core.ip, mp: self.core.mp });
+        }
+
+        if matches!(self.interrupt, Some(flag) if flag.load(Ordering::Relaxed)) {
+            return Err(RunError::Interrupted { ip: self.core.ip, mp: self.core.mp });
+        }
+        self.steps += 1;
+
+        if let Some(count) = self.counts.get_mut(self.core.ip) {
+            *count += 1;
+        }
+
+        if let (Some(count), Some(&Inst::LoopEnd(_))) = (self.iterations.get_mut(self.core.ip),
+                                                         self.program.code.get(self.core.ip)) {
+            if self.core.tape[self.core.mp] != T::ZERO {
+                *count += 1;
+            }
+        }
+
+        if self.trace {
+            eprintln!("ip={} {:?} mp={} cell={}", self.core.ip, self.program.code[self.core.ip],
+                      self.core.mp, self.core.tape[self.core.mp]);
+        }
+
+        Ok(())
+    }
+
+    // Replace the program while keeping the tape, the pointer and the
+    // counters, so the new program continues where the last one stopped
+    pub fn load(&mut self, program: Program) {
+        if self.profile {
+            self.counts = vec![0; program.code.len()];
+        }
+
+        if self.loop_profile {
+            self.iterations = vec![0; program.code.len()];
+        }
+
+        self.program = program;
+        self.core.ip = 0;
+    }
+
+    // Read 'input' before whatever is left of the input, like the input
+    // embedded in a program loaded after another one
+    pub fn prepend_input(&mut self, input: impl Read + 'a) {
+        let rest = mem::replace(&mut self.core.host.input, BufReader::new(Box::new(io::empty())));
+        self.core.host.input = BufReader::new(Box::new(input.chain(rest)));
+    }
+
+    // Start the virtual machine
+    pub fn start(&mut self) -> Result<(), RunError> {
+        self.run_to_completion().map(|_| ())
+    }
+
+    // Run the program until it finishes, returning what it did
+    pub fn run_to_completion(&mut self) -> Result<RunStats, RunError> {
+        // Only count the steps when there is no limit, profile, trace or
+        // interrupt to check for
+        if self.max_steps == u64::MAX && self.counts.is_empty() && self.iterations.is_empty() &&
+           !self.trace && self.interrupt.is_none() {
+            while self.core.ip < self.program.code.len() {
+                self.steps += 1;
+                self.core.ip = self.core.execute(&self.program)?;
+            }
+        }
+
+        while self.core.ip < self.program.code.len() {
+            self.account()?;
+            self.core.ip = self.core.execute(&self.program)?;
+        }
+
+        self.core.host.output.flush()?;
+        Ok(self.stats())
+    }
+
+    // What the program has done so far
+    pub fn stats(&self) -> RunStats {
+        RunStats {
+            instructions: self.steps,
+            inputs: self.core.host.inputs,
+            outputs: self.core.host.outputs,
+            written: self.core.host.written,
+            peak_mp: self.core.touched
+        }
+    }
+
+    // Execute a single instruction, returning whether the program is still running.
+    // The output is flushed once the program finishes
+    pub fn step(&mut self) -> Result<bool, RunError> {
+        if self.core.ip < self.program.code.len() {
+            self.account()?;
+            self.core.ip = self.core.execute(&self.program)?;
+        }
+
+        let running = self.core.ip < self.program.code.len();
+        if !running {
+            self.core.host.output.flush()?;
+        }
+
+        Ok(running)
+    }
+
+    // Write out any output buffered so far
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.core.host.output.flush()
+    }
+
+    // The FNV-1a hash of the bytes written so far, if it is being kept. The
+    // bytes are hashed after any formatting, just as they were written
+    pub fn digest(&self) -> Option<u64> {
+        self.core.host.digest
+    }
+
+    // The index of the next instruction to be executed
+    pub fn ip(&self) -> usize {
+        self.core.ip
+    }
+
+    // The next instruction to be executed, if the program is still running
+    pub fn current(&self) -> Option<&Inst> {
+        self.program.code.get(self.core.ip)
+    }
+
+    // The position of the memory pointer
+    pub fn pointer(&self) -> usize {
+        self.core.mp
+    }
+
+    // The tape the program is currently using, 0 unless switched with '@'
+    pub fn active_tape(&self) -> usize {
+        self.core.host.active_tape
+    }
+
+    // Stop running with an error once the flag is set, such as from a signal
+    // handler. The flag is checked before every instruction
+    pub fn interrupt_on(&mut self, flag: &'a AtomicBool) {
+        self.interrupt = Some(flag);
+    }
+
+    // The cells around the pointer, with the current one in brackets
+    pub fn nearby(&self) -> String {
+        nearby(&self.core.tape, self.core.mp)
+    }
+
+    // The cells of the active tape, for inspecting it after a run
+    pub fn tape(&self) -> &[T] {
+        &self.core.tape
+    }
+
+    // The value of a cell, if the address lies within the tape
+    pub fn cell(&self, address: usize) -> Option<T> {
+        self.core.tape.get(address).copied()
+    }
+
+    // Write the instructions executed the most when profiling, along with
+    // where they are in the source
+    pub fn profile(&self, out: &mut impl Write, limit: usize) -> io::Result<()> {
+        let mut hottest: Vec<usize> = (0..self.counts.len())
+            .filter(|&ip| self.counts[ip] > 0)
+            .collect();
+        hottest.sort_by(|&a, &b| self.counts[b].cmp(&self.counts[a]).then(a.cmp(&b)));
+
+        writeln!(out, "{:>12} {:>8} {:>12}  instruction", "count", "ip", "location")?;
+        for &ip in hottest.iter().take(limit) {
+            let (line, column) = self.program.position(ip);
+            writeln!(out, "{:>12} {:>8} {:>12}  {:?}", self.counts[ip], ip,
+                     format!("{}:{}", line, column), self.program.code[ip])?;
+        }
+
+        Ok(())
+    }
+
+    // Write the loops which jumped back to their start the most, from their
+    // '[' to their ']' in the source
+    pub fn loop_profile(&self, out: &mut impl Write, limit: usize) -> io::Result<()> {
+        let mut hottest: Vec<(usize, usize)> = self.program.code.iter().enumerate()
+            .filter_map(|(ip, inst)| match *inst {
+                Inst::LoopEnd(start) if self.iterations[ip] > 0 => Some((start, ip)),
+                _ => None
+            })
+            .collect();
+        hottest.sort_by(|&(a, x), &(b, y)| {
+            self.iterations[y].cmp(&self.iterations[x]).then(a.cmp(&b))
+        });
+
+        writeln!(out, "{:>12} {:>8} {:>24}", "iterations", "ip", "location")?;
+        for &(start, end) in hottest.iter().take(limit) {
+            let (line, column) = self.program.position(start);
+            let (end_line, end_column) = self.program.position(end);
+            writeln!(out, "{:>12} {:>8} {:>24}", self.iterations[end], start,
+                     format!("{}:{}-{}:{}", line, column, end_line, end_column))?;
+        }
+
+        Ok(())
+    }
+
+    // Write out the tapes, their pointers and the next instruction, for
+    // 'load_state' to pick up from in a later run of the same program. The
+    // input read so far is not part of the state
+    pub fn save_state(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut words = vec![fingerprint(&self.program.code), mem::size_of::<T>() as u64,
+                             self.core.ip as u64, self.core.host.active_tape as u64];
+        for &(mp, tape) in &[(self.core.mp, &self.core.tape), (self.core.host.other_mp, &self.core.host.other)] {
+            words.extend(&[mp as u64, tape.len() as u64]);
+            words.extend(tape.iter().map(|cell| cell.to_usize() as u64));
+        }
+
+        let mut bytes = STATE_MAGIC.to_vec();
+        for word in words {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        out.write_all(&bytes)
+    }
+
+    // Pick up from a state written by 'save_state', which must have been saved
+    // while running the same program on cells of the same size
+    pub fn load_state(&mut self, input: &mut impl Read) -> io::Result<()> {
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes)?;
+        let mut words = bytes.strip_prefix(STATE_MAGIC)
+            .ok_or_else(|| invalid("not a saved state"))?
+            .chunks(8)
+            .map(|chunk| <[u8; 8]>::try_from(chunk).ok().map(u64::from_le_bytes));
+        let mut next = || words.next().flatten().ok_or_else(|| invalid("the saved state is cut short"));
+
+        if next()? != fingerprint(&self.program.code) {
+            return Err(invalid("the state was saved from a different program"));
+        }
+
+        if next()? != mem::size_of::<T>() as u64 {
+            return Err(invalid("the state was saved with cells of a different size"));
+        }
+
+        let ip = next()? as usize;
+        let active_tape = next()? as usize;
+        let mut tapes = vec![];
+        for _ in 0..2 {
+            let mp = next()? as usize;
+            let mut tape = vec![];
+            for _ in 0..next()? {
+                tape.push(T::ZERO.add(next()? as usize));
+            }
+            tapes.push((mp, tape));
+        }
+
+        let (other_mp, other) = tapes.pop().unwrap_or_default();
+        let (mp, memory) = tapes.pop().unwrap_or_default();
+        // The other tape is empty until it is first switched to, and its
+        // pointer starts at zero
+        if ip > self.program.code.len() || active_tape > 1 || mp >= memory.len() ||
+            other_mp >= other.len().max(1) {
+            return Err(invalid("the saved state is not consistent"));
+        }
+
+        let touched = |tape: &[T], mp| tape.iter().rposition(|&cell| cell != T::ZERO).unwrap_or(0).max(mp);
+        self.core.touched = touched(&memory, mp);
+        self.core.host.other_touched = touched(&other, other_mp);
+        self.core.tape = memory;
+        self.core.host.other = other;
+        self.core.mp = mp;
+        self.core.host.other_mp = other_mp;
+        self.core.host.active_tape = active_tape;
+        self.core.ip = ip;
+        Ok(())
+    }
+
+    // Write a hex dump of the tape up to the highest cell ever visited, with
+    // the current cell enclosed in brackets
+    pub fn dump(&self, out: &mut impl Write) -> io::Result<()> {
+        let width = mem::size_of::<T>() * 2;
+
+        for (row, cells) in self.core.tape[..=self.core.touched].chunks(16).enumerate() {
+            write!(out, "{:08x}:", row * 16)?;
+            for (column, cell) in cells.iter().enumerate() {
+                let index = row * 16 + column;
+                let separator = if index == self.core.mp {
+                    '['
+                } else if column > 0 && index - 1 == self.core.mp {
+                    ']'
+                } else {
+                    ' '
+                };
+
+                write!(out, "{}{:0width$x}", separator, cell, width = width)?;
+            }
+
+            if (row * 16 + cells.len()).checked_sub(1) == Some(self.core.mp) {
+                write!(out, "]")?;
+            }
+            writeln!(out)?;
+        }
+
+        writeln!(out, "mp = {}", self.core.mp)
+    }
+
+    // Write how many of the visited cells hold each value, one value a line
+    // in ascending order, leaving out the values which no cell holds
+    pub fn histogram(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut counts = BTreeMap::new();
+        for &cell in &self.core.tape[..=self.core.touched] {
+            counts.entry(cell.to_usize() as isize).or_insert((cell, 0)).1 += 1;
+        }
+
+        for (cell, count) in counts.values() {
+            writeln!(out, "{:>6}: {}", cell, count)?;
+        }
+        Ok(())
+    }
+}
+
+// Run a compiled program on an 8-bit tape with the given input and output.
+// The program is validated first, since it may have been built by hand
+#[cfg(not(no_std))]
+pub fn run(program: &Program,
+           input: &mut impl Read,
+           output: &mut impl Write) -> Result<(), RunError> {
+    validate(&program.code)?;
+
+    let mut vm: Vm<u8> = Vm::new(program.clone(), &Config::default(),
+                                 Box::new(input), Box::new(output));
+    vm.start()
+}
+
+// Write out a compiled program one numbered instruction per line
+pub fn disassemble(program: &[Inst]) -> String {
+    program.iter()
+        .enumerate()
+        .map(|(index, inst)| format!("{:>8}  {}\n", index, inst))
+        .collect()
+}
+
+// The start of every program written by 'encode', which changes along with
+// the encoding
+const ENCODING_MAGIC: &[u8] = b"BFC\x02";
+
+// Write a compiled program in a compact binary form which 'decode' reads
+// back. Everything after the header is a little endian 64-bit word: the
+// number of instructions, each tag followed by its operands, the line and
+// column of every instruction, and then those of every delta and final move
+// of each block
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut words = encode_code(&program.code);
+    for index in 0..program.code.len() {
+        let (line, column) = program.position(index);
+        words.extend(&[line as u64, column as u64]);
+    }
+
+    for inst in &program.code {
+        if let Inst::Block { ref deltas, ref positions, .. } = *inst {
+            for index in 0..=deltas.len() {
+                let (line, column) = positions.get(index).copied().unwrap_or((0, 0));
+                words.extend(&[line as u64, column as u64]);
+            }
+        }
+    }
+
+    let mut bytes = ENCODING_MAGIC.to_vec();
+    for word in words {
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+// The words of 'encode' for the instructions alone, without their positions
+fn encode_code(code: &[Inst]) -> Vec<u64> {
+    use Inst::*;
+
+    let mut words: Vec<u64> = vec![code.len() as u64];
+    for inst in code {
+        match *inst {
+            Inc(amount) => words.extend(&[0, amount as u64]),
+            Dec(amount) => words.extend(&[1, amount as u64]),
+            ShiftRight(amount) => words.extend(&[2, amount as u64]),
+            ShiftLeft(amount) => words.extend(&[3, amount as u64]),
+            Input(amount) => words.extend(&[4, amount as u64]),
+            Output(amount) => words.extend(&[5, amount as u64]),
+            LoopStart(end) => words.extend(&[6, end as u64]),
+            LoopEnd(start) => words.extend(&[7, start as u64]),
+            SetZero => words.push(8),
+            SetConst(value) => words.extend(&[9, value as u64]),
+            MulAdd { offset, factor } => words.extend(&[10, offset as u64, factor as u64]),
+            AddAt { offset, amount } => words.extend(&[11, offset as u64, amount as u64]),
+            Block { ref deltas, shift, .. } => {
+                words.extend(&[12, shift as u64, deltas.len() as u64]);
+                for &(offset, amount) in deltas {
+                    words.extend(&[offset as u64, amount as u64]);
+                }
+            },
+            ScanRight => words.push(13),
+            ScanLeft => words.push(14),
+            Debug => words.push(15),
+            PrintPointer => words.push(16),
+            SwapTape => words.push(17),
+            ClearRange { len } => words.extend(&[18, len as u64]),
+        }
+    }
+    words
+}
+
+// An FNV-1a hash of the instructions of a program, telling apart the
+// programs a saved state belongs to
+#[cfg(not(no_std))]
+fn fingerprint(code: &[Inst]) -> u64 {
+    encode_code(code).iter()
+        .flat_map(|word| word.to_le_bytes())
+        .fold(FNV_OFFSET, |hash, byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+// Read back a program written by 'encode', if the bytes are one. The loops
+// are not checked, so a program from anywhere untrusted should be validated
+pub fn decode(bytes: &[u8]) -> Option<Program> {
+    use Inst::*;
+
+    let mut words = bytes.strip_prefix(ENCODING_MAGIC)?
+        .chunks(8)
+        .map(|chunk| <[u8; 8]>::try_from(chunk).ok().map(u64::from_le_bytes));
+    let mut next = || words.next().flatten().and_then(|word| usize::try_from(word).ok());
+
+    let mut program = Program::default();
+    for _ in 0..next()? {
+        let inst = match next()? {
+            0 => Inc(next()?),
+            1 => Dec(next()?),
+            2 => ShiftRight(next()?),
+            3 => ShiftLeft(next()?),
+            4 => Input(next()?),
+            5 => Output(next()?),
+            6 => LoopStart(next()?),
+            7 => LoopEnd(next()?),
+            8 => SetZero,
+            9 => SetConst(next()?),
+            10 => MulAdd { offset: next()? as isize, factor: next()? },
+            11 => AddAt { offset: next()? as isize, amount: next()? },
+            12 => {
+                let shift = next()? as isize;
+                let mut deltas = vec![];
+                for _ in 0..next()? {
+                    deltas.push((next()? as isize, next()?));
+                }
+                Block { deltas, shift, positions: vec![] }
+            },
+            13 => ScanRight,
+            14 => ScanLeft,
+            15 => Debug,
+            16 => PrintPointer,
+            17 => SwapTape,
+            18 => ClearRange { len: next()? },
+            _ => return None
+        };
+        program.code.push(inst);
+    }
+
+    for _ in 0..program.code.len() {
+        program.positions.push((next()?, next()?));
+    }
+
+    for inst in &mut program.code {
+        if let Block { ref deltas, ref mut positions, .. } = *inst {
+            for _ in 0..=deltas.len() {
+                positions.push((next()?, next()?));
+            }
+        }
+    }
+
+    match next() {
+        Some(_) => None,
+        None => Some(program)
+    }
+}
+
+// Translate a compiled program to an equivalent C program. The pointer is an
+// index which wraps around the ends of the tape, just like in the VM
+#[cfg(not(no_std))]
+pub fn emit_c(program: &[Inst], tape_size: usize, out: &mut impl Write) -> io::Result<()> {
+    use Inst::*;
+
+    // The cell an offset away from the pointer
+    let cell = |offset: isize| format!("m[(p + {}) % TAPE]", offset.rem_euclid(tape_size as isize));
+
+    writeln!(out, "#include <stdio.h>")?;
+    writeln!(out)?;
+    writeln!(out, "#define TAPE {}", tape_size)?;
+    writeln!(out)?;
+    let dual_tape = program.iter().any(|inst| matches!(inst, SwapTape));
+
+    writeln!(out, "static unsigned char tape[TAPE];")?;
+    if dual_tape {
+        writeln!(out, "static unsigned char aux[TAPE];")?;
+    }
+    writeln!(out)?;
+    writeln!(out, "int main(void)")?;
+    writeln!(out, "{{")?;
+    writeln!(out, "    unsigned char *m = tape;")?;
+    writeln!(out, "    size_t p = 0;")?;
+    if dual_tape {
+        writeln!(out, "    unsigned char *n = aux;")?;
+        writeln!(out, "    size_t q = 0;")?;
+    }
+
+    let mut depth = 1;
+    for inst in program {
+        if let LoopEnd(_) = *inst {
+            depth -= 1;
+        }
+
+        write!(out, "{:1$}", "", depth * 4)?;
+        match *inst {
+            Inc(amount) => writeln!(out, "m[p] += {};", amount as u8)?,
+            Dec(amount) => writeln!(out, "m[p] -= {};", amount as u8)?,
+            ShiftRight(amount) => writeln!(out, "p = (p + {}) % TAPE;", amount % tape_size)?,
+            ShiftLeft(amount) => writeln!(out, "p = (p + TAPE - {}) % TAPE;", amount % tape_size)?,
+            Input(1) => writeln!(out, "{{ int c = getchar(); m[p] = c == EOF ? 0 : c; }}")?,
+            Input(amount) => writeln!(out, "for (int i = 0; i < {}; i++) \
+                                           {{ int c = getchar(); m[p] = c == EOF ? 0 : c; }}", amount)?,
+            Output(1) => writeln!(out, "putchar(m[p]);")?,
+            Output(amount) => writeln!(out, "for (int i = 0; i < {}; i++) putchar(m[p]);", amount)?,
+            LoopStart(_) => writeln!(out, "while (m[p]) {{")?,
+            LoopEnd(_) => writeln!(out, "}}")?,
+            SetZero => writeln!(out, "m[p] = 0;")?,
+            SetConst(value) => writeln!(out, "m[p] = {};", value as u8)?,
+            ScanRight => writeln!(out, "while (m[p]) p = (p + 1) % TAPE;")?,
+            ScanLeft => writeln!(out, "while (m[p]) p = (p + TAPE - 1) % TAPE;")?,
+            Debug => writeln!(out, "/* # */")?,
+            PrintPointer => writeln!(out, "/* $ */")?,
+            SwapTape => writeln!(out, "{{ unsigned char *t = m; m = n; n = t; size_t s = p; p = q; q = s; }}")?,
+            MulAdd { offset, factor } => writeln!(out, "{} += m[p] * {};", cell(offset), factor as u8)?,
+            AddAt { offset, amount } => writeln!(out, "{} += {};", cell(offset), amount as u8)?,
+            Block { ref deltas, shift, .. } => {
+                for &(offset, amount) in deltas {
+                    write!(out, "{} += {}; ", cell(offset), amount as u8)?;
+                }
+                writeln!(out, "p = (p + {}) % TAPE;", shift.rem_euclid(tape_size as isize))?
+            },
+            ClearRange { len } => writeln!(out, "m[p] = 0; for (int i = 1; i < {}; i++) \
+                                               {{ p = (p + 1) % TAPE; m[p] = 0; }}", len)?
+        }
+
+        if let LoopStart(_) = *inst {
+            depth += 1;
+        }
+    }
+
+    writeln!(out, "    return 0;")?;
+    writeln!(out, "}}")
+}
+
+// Translate a compiled program to an equivalent standalone Rust program
+#[cfg(not(no_std))]
+pub fn emit_rust(program: &[Inst], tape_size: usize, out: &mut impl Write) -> io::Result<()> {
+    use Inst::*;
+
+    writeln!(out, "#![allow(unused)]")?;
+    writeln!(out)?;
+    writeln!(out, "use std::io::{{stdin, stdout, BufWriter, Read, Write}};")?;
+    writeln!(out)?;
+    writeln!(out, "const TAPE: usize = {};", tape_size)?;
+    writeln!(out)?;
+    writeln!(out, "fn main() {{")?;
+    writeln!(out, "    let mut tape = vec![0u8; TAPE];")?;
+    writeln!(out, "    let mut p: usize = 0;")?;
+    if program.iter().any(|inst| matches!(inst, SwapTape)) {
+        writeln!(out, "    let mut aux = vec![0u8; TAPE];")?;
+        writeln!(out, "    let mut q: usize = 0;")?;
+    }
+    writeln!(out, "    let mut input = stdin().bytes();")?;
+    writeln!(out, "    let mut output = BufWriter::new(stdout());")?;
+
+    let mut depth = 1;
+    for inst in program {
+        if let LoopEnd(_) = *inst {
+            depth -= 1;
+        }
+
+        write!(out, "{:1$}", "", depth * 4)?;
+        match *inst {
+            Inc(amount) => writeln!(out, "tape[p] = tape[p].wrapping_add({});", amount as u8)?,
+            Dec(amount) => writeln!(out, "tape[p] = tape[p].wrapping_sub({});", amount as u8)?,
+            ShiftRight(amount) => writeln!(out, "p = (p + {}) % TAPE;", amount % tape_size)?,
+            ShiftLeft(amount) => writeln!(out, "p = (p + TAPE - {}) % TAPE;", amount % tape_size)?,
+            Input(amount) => {
+                writeln!(out, "output.flush().unwrap();")?;
+                for _ in 0..amount {
+                    write!(out, "{:1$}", "", depth * 4)?;
+                    writeln!(out, "tape[p] = input.next().and_then(|b| b.ok()).unwrap_or(0);")?;
+                }
+            },
+            Output(1) => writeln!(out, "output.write_all(&[tape[p]]).unwrap();")?,
+            Output(amount) => writeln!(out, "output.write_all(&[tape[p]; {}]).unwrap();", amount)?,
+            LoopStart(_) => writeln!(out, "while tape[p] != 0 {{")?,
+            LoopEnd(_) => writeln!(out, "}}")?,
+            SetZero => writeln!(out, "tape[p] = 0;")?,
+            SetConst(value) => writeln!(out, "tape[p] = {};", value as u8)?,
+            ScanRight => writeln!(out, "while tape[p] != 0 {{ p = (p + 1) % TAPE; }}")?,
+            ScanLeft => writeln!(out, "while tape[p] != 0 {{ p = (p + TAPE - 1) % TAPE; }}")?,
+            Debug => writeln!(out, "// #")?,
+            PrintPointer => writeln!(out, "// $")?,
+            SwapTape => {
+                writeln!(out, "std::mem::swap(&mut tape, &mut aux);")?;
+                write!(out, "{:1$}", "", depth * 4)?;
+                writeln!(out, "std::mem::swap(&mut p, &mut q);")?;
+            },
+            MulAdd { offset, factor } => {
+                writeln!(out, "let t = (p as isize + {}).rem_euclid(TAPE as isize) as usize;",
+                         offset)?;
+                write!(out, "{:1$}", "", depth * 4)?;
+                writeln!(out, "tape[t] = tape[t].wrapping_add(tape[p].wrapping_mul({}));",
+                         factor as u8)?;
+            },
+            AddAt { offset, amount } => {
+                writeln!(out, "let t = (p as isize + {}).rem_euclid(TAPE as isize) as usize;",
+                         offset)?;
+                write!(out, "{:1$}", "", depth * 4)?;
+                writeln!(out, "tape[t] = tape[t].wrapping_add({});", amount as u8)?;
+            },
+            Block { ref deltas, shift, .. } => {
+                for &(offset, amount) in deltas {
+                    writeln!(out, "let t = (p as isize + {}).rem_euclid(TAPE as isize) as usize;",
+                             offset)?;
+                    write!(out, "{:1$}", "", depth * 4)?;
+                    writeln!(out, "tape[t] = tape[t].wrapping_add({});", amount as u8)?;
+                    write!(out, "{:1$}", "", depth * 4)?;
+                }
+                writeln!(out, "p = (p as isize + {}).rem_euclid(TAPE as isize) as usize;", shift)?;
+            },
+            ClearRange { len } => {
+                writeln!(out, "tape[p] = 0;")?;
+                write!(out, "{:1$}", "", depth * 4)?;
+                writeln!(out, "for _ in 1..{} {{ p = (p + 1) % TAPE; tape[p] = 0; }}", len)?;
+            }
+        }
+
+        if let LoopStart(_) = *inst {
+            depth += 1;
+        }
+    }
+
+    writeln!(out, "    output.flush().unwrap();")?;
+    writeln!(out, "}}")
+}
+
+// Translate a compiled program to an equivalent WebAssembly text module. The
+// tape lives in linear memory and the module imports 'putchar' and
+// 'getchar' from "env", where 'getchar' returns -1 at the end of input. The
+// program runs when the exported 'main' is called
+#[cfg(not(no_std))]
+pub fn emit_wat(program: &[Inst], tape_size: usize, out: &mut impl Write) -> io::Result<()> {
+    use Inst::*;
+
+    // The second tape of the '@' extension lies right after the first one,
+    // and '$base' is where the active one starts
+    let dual_tape = program.iter().any(|inst| matches!(inst, SwapTape));
+    let memory = if dual_tape { tape_size * 2 } else { tape_size };
+    let at = |pointer: &str| if dual_tape {
+        format!("(i32.add (local.get $base) (local.get {}))", pointer)
+    } else {
+        format!("(local.get {})", pointer)
+    };
+
+    // Move a pointer by an offset, wrapping around the ends of the tape
+    let moved = |pointer: &str, offset: isize| format!(
+        "(i32.rem_u (i32.add (local.get {}) (i32.const {})) (i32.const {}))",
+        pointer, offset.rem_euclid(tape_size as isize), tape_size);
+
+    writeln!(out, "(module")?;
+    writeln!(out, "  (import \"env\" \"putchar\" (func $putchar (param i32)))")?;
+    writeln!(out, "  (import \"env\" \"getchar\" (func $getchar (result i32)))")?;
+    writeln!(out, "  (memory (export \"memory\") {})", memory.div_ceil(0x10000))?;
+    writeln!(out, "  (func (export \"main\")")?;
+    writeln!(out, "    (local $p i32) (local $q i32) (local $c i32)")?;
+    if dual_tape {
+        writeln!(out, "    (local $base i32) (local $other i32)")?;
+    }
+
+    let p = at("$p");
+    let q = at("$q");
+    let mut depth = 2;
+    for (index, inst) in program.iter().enumerate() {
+        if let LoopEnd(_) = *inst {
+            depth -= 1;
+        }
+
+        write!(out, "{:1$}", "", depth * 2)?;
+        match *inst {
+            Inc(amount) => writeln!(out, "(i32.store8 {} (i32.add (i32.load8_u {}) (i32.const {})))",
+                                    p, p, amount as u8)?,
+            Dec(amount) => writeln!(out, "(i32.store8 {} (i32.sub (i32.load8_u {}) (i32.const {})))",
+                                    p, p, amount as u8)?,
+            ShiftRight(amount) => writeln!(out, "(local.set $p {})", moved("$p", amount as isize))?,
+            ShiftLeft(amount) => writeln!(out, "(local.set $p {})", moved("$p", -(amount as isize)))?,
+            Input(amount) => {
+                for i in 0..amount {
+                    if i > 0 {
+                        write!(out, "{:1$}", "", depth * 2)?;
+                    }
+                    writeln!(out, "(local.set $c (call $getchar))")?;
+                    write!(out, "{:1$}", "", depth * 2)?;
+                    writeln!(out, "(i32.store8 {} (select (local.get $c) (i32.const 0) \
+                                   (i32.ge_s (local.get $c) (i32.const 0))))", p)?;
+                }
+            },
+            Output(amount) => {
+                for i in 0..amount {
+                    if i > 0 {
+                        write!(out, "{:1$}", "", depth * 2)?;
+                    }
+                    writeln!(out, "(call $putchar (i32.load8_u {}))", p)?;
+                }
+            },
+            LoopStart(_) => {
+                writeln!(out, "(block $b{} (loop $l{}", index, index)?;
+                write!(out, "{:1$}", "", depth * 2 + 2)?;
+                writeln!(out, "(br_if $b{} (i32.eqz (i32.load8_u {})))", index, p)?;
+            },
+            LoopEnd(start) => writeln!(out, "  (br $l{})))", start)?,
+            SetZero => writeln!(out, "(i32.store8 {} (i32.const 0))", p)?,
+            SetConst(value) => writeln!(out, "(i32.store8 {} (i32.const {}))", p, value as u8)?,
+            ScanRight | ScanLeft => {
+                let step = if let ScanRight = *inst { 1 } else { -1 };
+                writeln!(out, "(block (loop (br_if 1 (i32.eqz (i32.load8_u {}))) \
+                               (local.set $p {}) (br 0)))", p, moved("$p", step))?;
+            },
+            Debug => writeln!(out, ";; #")?,
+            PrintPointer => writeln!(out, ";; $")?,
+            SwapTape => {
+                writeln!(out, "(local.set $c (local.get $p)) (local.set $p (local.get $other)) \
+                               (local.set $other (local.get $c))")?;
+                write!(out, "{:1$}", "", depth * 2)?;
+                writeln!(out, "(local.set $base (i32.sub (i32.const {}) (local.get $base)))",
+                         tape_size)?;
+            },
+            MulAdd { offset, factor } => {
+                writeln!(out, "(local.set $q {})", moved("$p", offset))?;
+                write!(out, "{:1$}", "", depth * 2)?;
+                writeln!(out, "(i32.store8 {} (i32.add (i32.load8_u {}) \
+                               (i32.mul (i32.load8_u {}) (i32.const {}))))",
+                         q, q, p, factor as u8)?;
+            },
+            AddAt { offset, amount } => {
+                writeln!(out, "(local.set $q {})", moved("$p", offset))?;
+                write!(out, "{:1$}", "", depth * 2)?;
+                writeln!(out, "(i32.store8 {} (i32.add (i32.load8_u {}) (i32.const {})))",
+                         q, q, amount as u8)?;
+            },
+            Block { ref deltas, shift, .. } => {
+                for &(offset, amount) in deltas {
+                    writeln!(out, "(local.set $q {})", moved("$p", offset))?;
+                    write!(out, "{:1$}", "", depth * 2)?;
+                    writeln!(out, "(i32.store8 {} (i32.add (i32.load8_u {}) (i32.const {})))",
+                             q, q, amount as u8)?;
+                    write!(out, "{:1$}", "", depth * 2)?;
+                }
+                writeln!(out, "(local.set $p {})", moved("$p", shift))?;
+            },
+            ClearRange { len } => {
+                for offset in 0..len as isize {
+                    writeln!(out, "(local.set $q {})", moved("$p", offset))?;
+                    write!(out, "{:1$}", "", depth * 2)?;
+                    writeln!(out, "(i32.store8 {} (i32.const 0))", q)?;
+                    write!(out, "{:1$}", "", depth * 2)?;
+                }
+                writeln!(out, "(local.set $p {})", moved("$p", len as isize - 1))?;
+            }
+        }
+
+        if let LoopStart(_) = *inst {
+            depth += 1;
+        }
+    }
+
+    writeln!(out, "  )")?;
+    writeln!(out, ")")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Compile a program with the default options, without optimizing it
+    fn compiled(source: &str) -> Program {
+        compile(source, &CompileOptions::default()).expect("test programs compile")
+    }
+
+    // Run an optimized program against an in-memory input, returning all of
+    // its output
+    fn output(source: &str, input: &[u8], config: &Config) -> Vec<u8> {
+        let mut output = vec![];
+        {
+            let input = Box::new(io::Cursor::new(input.to_vec()));
+            let program = optimize(compiled(source));
+            let mut vm: Vm<u8> = Vm::new(program, config, input, Box::new(&mut output));
+            vm.run_to_completion().expect("test programs run");
+        }
+        output
+    }
+
+    // Run a program to completion on a short tape, returning the pointer and
+    // the cells
+    fn finish(program: Program, config: &Config) -> Result<(usize, Vec<u8>), RunError> {
+        let config = Config { tape_size: 16, ..config.clone() };
+        let mut vm: Vm<u8> = Vm::new(program, &config, Box::new(io::empty()), Box::new(io::sink()));
+        vm.run_to_completion()?;
+        Ok((vm.pointer(), vm.tape().to_vec()))
+    }
+
+    // The pointer and cells a program leaves, checked to be the same with and
+    // without optimizing it
+    fn tape(source: &str) -> (usize, Vec<u8>) {
+        let config = Config::default();
+        let naive = finish(compiled(source), &config).expect("test programs run");
+        let optimized = finish(optimize(compiled(source)), &config).expect("test programs run");
+        assert_eq!(naive, optimized, "optimizing changed what {:?} does", source);
+        naive
+    }
+
+    const ROT13: &str = "-,+[-[>>++++[>++++++++<-]<+<-[>+>+>-[>>>]<[[>+<-]>>+>]<<<<<-]]>>>[-]+>--[-[<->+++[-]]\
+                         ]<[++++++++++++<[>-[>+>>]>[+[<+>-]>+>>]<<<<<-]>>[<+>-]>[-[-<<[-]>>]<<[<<->>-]>>]<<[<<+\
+                         >>-]]<[-]<.[-]<-,+]";
+
+    #[test]
+    fn hello_world() {
+        let source = include_str!("examples/hello_world.brainfuck");
+        assert_eq!(output(source, b"", &Config::default()), b"Hello World!\n");
+    }
+
+    #[test]
+    fn cat() {
+        assert_eq!(output(",[.,]", b"cat\n", &Config::default()), b"cat\n");
+    }
+
+    #[test]
+    fn rot13() {
+        let config = Config { eof: Eof::Unchanged, ..Config::default() };
+        assert_eq!(output(ROT13, b"Hello, World!", &config), b"Uryyb, Jbeyq!");
+    }
+
+    #[test]
+    fn disassemble_folds_runs() {
+        assert_eq!(disassemble(&compiled("++[->+<]").code), "       0  add 2
+       1  jz 6
+       2  sub 1
+       3  shr 1
+       4  add 1
+       5  shl 1
+       6  jnz 1
+");
+        assert_eq!(disassemble(&compiled(",.").code), "       0  in 1
+       1  out 1
+");
+        assert_eq!(disassemble(&compiled("+++++.").code), "       0  add 5
+       1  out 1
+");
+    }
+
+    #[test]
+    fn corrupt_jumps_are_rejected() {
+        let program = Program {
+            code: vec![Inst::Inc(1), Inst::LoopStart(7), Inst::Dec(1)],
+            positions: vec![(1, 1), (1, 2), (1, 3)]
+        };
+
+        match run(&program, &mut io::empty(), &mut io::sink()) {
+            Err(RunError::Invalid(err)) => assert_eq!(err.kind, CompileErrorKind::MismatchedJump(1)),
+            other => panic!("expected a corrupt program to be rejected, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn clear_loops_shrink() {
+        assert!(matches!(*optimize(compiled("[-]")).code, [Inst::SetZero]));
+        assert!(matches!(*optimize(compiled("[+]")).code, [Inst::SetZero]));
+
+        let source = include_str!("examples/factorials.brainfuck");
+        assert!(optimize(compiled(source)).code.len() < compiled(source).code.len());
+    }
+
+    #[test]
+    fn copy_loop() {
+        assert_eq!(disassemble(&optimize(compiled("[->+<]")).code), "       0  muladd +1 1
+       1  zero
+");
+        assert_eq!(tape("+++[->+<]").1[..3], [0, 3, 0]);
+    }
+
+    #[test]
+    fn multiply_loop() {
+        assert_eq!(disassemble(&optimize(compiled("[->++>+++<<]")).code), "       0  muladd +1 2
+       1  muladd +2 3
+       2  zero
+");
+        assert_eq!(tape("++++[->++>+++<<]").1[..4], [0, 8, 12, 0]);
+    }
+
+    #[test]
+    fn unterminated_names_outer_bracket() {
+        let err = compile("+\n[[+]", &CompileOptions::default()).unwrap_err();
+        assert_eq!((err.kind, err.line, err.column), (CompileErrorKind::UnterminatedOpen, 2, 1));
+    }
+
+    #[test]
+    fn leading_loop_is_code() {
+        let source = "[this loop is skipped]++++++++[>++++++++<-]>+.";
+        assert_eq!(output(source, b"", &Config::default()), b"A");
+        assert!(matches!(compiled(source).code[0], Inst::LoopStart(_)));
+
+        let options = CompileOptions { comment_loop: true, ..CompileOptions::default() };
+        let program = compile(source, &options).unwrap();
+        assert!(!matches!(program.code[0], Inst::LoopStart(_)));
+    }
+
+    #[test]
+    fn scans_land_where_loops_do() {
+        assert!(matches!(*optimize(compiled("[>]")).code, [Inst::ScanRight]));
+        assert!(matches!(*optimize(compiled("[<]")).code, [Inst::ScanLeft]));
+
+        assert_eq!(tape("+>+>+>>+<<<<[>]").0, 3);
+        assert_eq!(tape(">+>+>+[<]").0, 0);
+    }
+
+    #[test]
+    fn input_is_buffered_across_reads() {
+        assert_eq!(output(",.>,.>,.", b"abc", &Config::default()), b"abc");
+        assert_eq!(output(",,,.,.", b"abcd", &Config::default()), b"cd");
+    }
+
+    #[test]
+    fn cancelling_runs() {
+        assert!(simplify(compiled("+++---")).code.is_empty());
+        assert!(simplify(compiled(">><<")).code.is_empty());
+        assert!(matches!(*simplify(compiled("+++--")).code, [Inst::Inc(1)]));
+        assert!(matches!(*simplify(compiled("++----")).code, [Inst::Dec(2)]));
+        assert!(matches!(*simplify(compiled(">>><")).code, [Inst::ShiftRight(2)]));
+        assert!(matches!(*simplify(compiled("><<<")).code, [Inst::ShiftLeft(2)]));
+    }
+
+    #[test]
+    fn signed_cells_wrap() {
+        let first = |source: &str| {
+            let (input, output) = (Box::new(io::empty()), Box::new(io::sink()));
+            let mut vm: Vm<i8> = Vm::new(compiled(source), &Config::default(), input, output);
+            vm.run_to_completion().unwrap();
+            vm.tape()[0]
+        };
+
+        assert_eq!(first(&"-".repeat(128)), -128);
+        assert_eq!(first(&"-".repeat(129)), 127);
+        assert_eq!(first(&"+".repeat(128)), -128);
+    }
+
+    #[test]
+    fn byte_positions() {
+        let source = "+ héllo ]";
+        let err = compile(source, &CompileOptions::default()).unwrap_err();
+        assert_eq!((err.kind, err.line, err.column), (CompileErrorKind::UnbalancedClose, 1, 9));
+
+        let options = CompileOptions { byte_positions: true, ..CompileOptions::default() };
+        let err = compile(source, &options).unwrap_err();
+        assert_eq!((err.line, err.column), (1, 10));
+    }
+
+    #[test]
+    fn offset_writes() {
+        assert_eq!(disassemble(&optimize(compiled(">+++<")).code), "       0  addat +1 3\n");
+        assert_eq!(disassemble(&optimize(compiled("<--->")).code), "       0  addat -1 -3\n");
+
+        assert_eq!(tape("++[>+++<-]>>-<<").1[..3], [0, 6, 255]);
+        assert_eq!(tape(">>+[<<+>>-]<++<").1[..3], [1, 2, 0]);
+    }
+
+    #[test]
+    fn wat_loops() {
+        let mut wat = vec![];
+        emit_wat(&compiled("+[>[-]<-]").code, TAPE_LENGTH, &mut wat).unwrap();
+        let wat = String::from_utf8(wat).unwrap();
+
+        let lines: Vec<&str> = wat.lines().map(str::trim)
+            .filter(|line| line.starts_with("(block") || line.starts_with("(br"))
+            .collect();
+        assert_eq!(lines, [
+            "(block $b1 (loop $l1",
+            "(br_if $b1 (i32.eqz (i32.load8_u (local.get $p))))",
+            "(block $b3 (loop $l3",
+            "(br_if $b3 (i32.eqz (i32.load8_u (local.get $p))))",
+            "(br $l3)))",
+            "(br $l1)))",
+        ]);
+    }
+
+    #[test]
+    fn saturating_cells() {
+        let saturate = Config { saturate: true, ..Config::default() };
+        assert_eq!(finish(compiled("-"), &saturate).unwrap().1[0], 0);
+        assert_eq!(finish(compiled("-"), &Config::default()).unwrap().1[0], 255);
+
+        let up = "+".repeat(300);
+        assert_eq!(finish(compiled(&up), &saturate).unwrap().1[0], 255);
+        assert_eq!(finish(compiled(&up), &Config::default()).unwrap().1[0], 44);
+    }
+
+    #[test]
+    fn ring_and_line_tapes() {
+        let (pointer, cells) = finish(compiled("<+"), &Config::default()).unwrap();
+        assert_eq!((pointer, cells[15]), (15, 1));
+
+        let line = Config { bounds: Bounds::Error, ..Config::default() };
+        for program in [compiled("<+"), optimize(compiled("<+"))] {
+            match finish(program, &line) {
+                Err(RunError::OutOfBounds { ip: 0, line: 1, column: 1 }) => {},
+                other => panic!("expected to move off the tape, got {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn naive_and_optimized_agree() {
+        let hello = include_str!("examples/hello_world.brainfuck");
+        for &(source, input) in &[(hello, &b""[..]), (",[.,]", &b"cat\n"[..])] {
+            let mut naive = vec![];
+            run(&compiled(source), &mut io::Cursor::new(input), &mut naive).unwrap();
+            assert_eq!(naive, output(source, input, &Config::default()));
+        }
+    }
+
+    #[test]
+    fn bytes_are_written_raw() {
+        let source = "+".repeat(200) + ".";
+        assert_eq!(output(&source, b"", &Config::default()), [0xc8]);
+    }
+
+    #[test]
+    fn long_lines_stream_their_end() {
+        let source = format!("+\n{}]", "a".repeat(1000));
+        let error = compile_reader(source.as_bytes(), &CompileOptions::default()).unwrap_err();
+        assert_eq!((error.line, error.column), (2, 1001));
+
+        let shown = error.context.lines().collect::<Vec<_>>();
+        let line = shown[1].trim_start_matches("    2 | ...");
+        assert!(line.ends_with("a]") && line.len() <= 3 * EXCERPT_COLUMNS);
+        assert_eq!(shown[2].find('^'), shown[1].find(']'));
+    }
+
+    #[test]
+    fn blocks_blame_the_move_off_the_tape() {
+        let line = Config { bounds: Bounds::Error, ..Config::default() };
+        let sources = [("+++\n\n<", (3, 1)), (">>+\n<<<<+", (2, 1)), ("+>\n+<<<-", (2, 2))];
+        for &(source, position) in &sources {
+            let program = optimize(compiled(source));
+            let decoded = decode(&encode(&program)).expect("programs decode");
+            for program in [program, decoded] {
+                match finish(program, &line) {
+                    Err(RunError::OutOfBounds { line, column, .. }) =>
+                        assert_eq!((line, column), position),
+                    other => panic!("expected {:?} to move off the tape, got {:?}", source, other)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dump_marks_the_pointer_once() {
+        let source = ">".repeat(16) + "+<";
+        let mut vm: Vm<u8> = Vm::new(compiled(&source), &Config::default(),
+                                     Box::new(io::empty()), Box::new(io::sink()));
+        vm.run_to_completion().unwrap();
+
+        let mut dump = vec![];
+        vm.dump(&mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        assert!(dump.lines().next().unwrap().ends_with("[00]"));
+        assert_eq!(dump.lines().nth(1), Some("00000010: 01"));
+    }
+
+    #[test]
+    fn prepended_input_is_read_first() {
+        let mut output = vec![];
+        {
+            let input = Box::new(io::Cursor::new(b"cd".to_vec()));
+            let mut vm: Vm<u8> = Vm::new(compiled(",."), &Config::default(), input, Box::new(&mut output));
+            vm.run_to_completion().unwrap();
+
+            vm.load(compiled(",.,.,."));
+            vm.prepend_input(&b"ab"[..]);
+            vm.run_to_completion().unwrap();
+        }
+        assert_eq!(output, b"cabd");
+    }
+
+    #[test]
+    fn output_limit_counts_bytes() {
+        let config = Config { numeric_output: true, max_output: Some(3), digest: true, ..Config::default() };
+        let mut output = vec![];
+        let (result, stats, digest) = {
+            let program = compiled(&("+".repeat(100) + ".."));
+            let mut vm: Vm<u8> = Vm::new(program, &config, Box::new(io::empty()), Box::new(&mut output));
+            (vm.run_to_completion(), vm.stats(), vm.digest())
+        };
+
+        match result {
+            Err(RunError::OutputLimit { limit: 3, .. }) => {},
+            other => panic!("expected to hit the output limit, got {:?}", other)
+        }
+        assert_eq!(output, b"100");
+        assert_eq!((stats.outputs, stats.written), (1, 3));
+
+        let hash = output.iter().fold(FNV_OFFSET, |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME));
+        assert_eq!(digest, Some(hash));
+    }
+
+    #[test]
+    fn states_point_on_their_tapes() {
+        let mut vm: Vm<u8> = Vm::new(compiled("+>+"), &Config::default(),
+                                     Box::new(io::empty()), Box::new(io::sink()));
+        vm.run_to_completion().unwrap();
+        let mut state = vec![];
+        vm.save_state(&mut state).unwrap();
+        assert!(vm.load_state(&mut &state[..]).is_ok());
+
+        // The pointer of the other tape, which was never switched to
+        let at = state.len() - 16;
+        state[at] = 5;
+        assert!(vm.load_state(&mut &state[..]).is_err());
+    }
+
+    #[test]
+    fn machines_run_on_borrowed_tapes() {
+        let program = optimize(compiled(include_str!("examples/hello_world.brainfuck")));
+        let mut tape = [0u8; 64];
+        let mut output = vec![];
+        Machine::new(&program, &Config::default(), &mut tape, 0, &b""[..], &mut output).run().unwrap();
+        assert_eq!(output, b"Hello World!\n");
+
+        let program = compiled(",[.,]");
+        let mut output = vec![];
+        Machine::new(&program, &Config::default(), &mut tape, 0, &b"cat"[..], &mut output).run().unwrap();
+        assert_eq!(output, b"cat");
+
+        let line = Config { bounds: Bounds::Error, ..Config::default() };
+        let program = optimize(compiled("+>+\n<<-"));
+        let mut machine = Machine::new(&program, &line, &mut tape, 0, &b""[..], vec![]);
+        assert_eq!(machine.run(), Err(Fault::OutOfBounds { ip: 0, line: 2, column: 1 }));
+    }
+
+    #[test]
+    fn annotated_output() {
+        let config = Config { annotate_output: true, ..Config::default() };
+        assert_eq!(output("++++++++++.", b"", &config), b"[10 0x0A '\\n']");
+    }
+
+    #[test]
+    fn echoed_input_is_counted() {
+        let config = Config { echo_input: true, max_output: Some(4), digest: true, ..Config::default() };
+        let mut output = vec![];
+        let (result, stats, digest) = {
+            let input = Box::new(io::Cursor::new(b"abc".to_vec()));
+            let mut vm: Vm<u8> = Vm::new(compiled(",.,.,."), &config, input, Box::new(&mut output));
+            (vm.run_to_completion(), vm.stats(), vm.digest())
+        };
+
+        match result {
+            Err(RunError::OutputLimit { ip: 4, limit: 4 }) => {},
+            other => panic!("expected the echo to hit the output limit, got {:?}", other)
+        }
+        assert_eq!(output, b"aabb");
+        assert_eq!((stats.outputs, stats.written), (2, 4));
+
+        let hash = output.iter().fold(FNV_OFFSET, |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME));
+        assert_eq!(digest, Some(hash));
+    }
+
+    // The pointer, the cells and the output a program leaves on a short tape
+    fn outcome(program: Program) -> (usize, Vec<u8>, Vec<u8>) {
+        let config = Config { tape_size: 16, ..Config::default() };
+        let mut output = vec![];
+        let (pointer, tape) = {
+            let mut vm: Vm<u8> = Vm::new(program, &config, Box::new(io::empty()), Box::new(&mut output));
+            vm.run_to_completion().expect("test programs run");
+            (vm.pointer(), vm.tape().to_vec())
+        };
+        (pointer, tape, output)
+    }
+
+    #[test]
+    fn unrolled_loops_run_the_same() {
+        let loops = |program: &Program| program.code.iter().filter(|inst| matches!(inst, Inst::LoopStart(_))).count();
+
+        // The last two keep their loop, one running more often than the limit
+        // and the other on a cell whose value is not known
+        for &(source, left) in &[("[-]+++[.-]", 0), ("[-]+++[>+>.<<-]", 0), ("[-]+++++[.-]", 1), (",[.-]", 1)] {
+            let program = optimize(compiled(source));
+            let unrolled = unroll(program.clone(), 4);
+            assert_eq!(loops(&unrolled), left, "unrolling {:?}", source);
+            assert_eq!(outcome(unrolled), outcome(program), "unrolling changed what {:?} does", source);
+        }
+
+        let (pointer, tape, output) = outcome(unroll(optimize(compiled("[-]+++[>+>.<<-]")), 4));
+        assert_eq!((pointer, &tape[..3], output), (0, &[0, 3, 0][..], vec![0; 3]));
+    }
+
+    // A source far larger than anything worth holding in memory, mostly
+    // comments with a command among them
+    struct Comments {
+        left: usize,
+        line: &'static [u8],
+        offset: usize,
+    }
+
+    impl Read for Comments {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let mut length = 0;
+            while length < buffer.len() && self.left > 0 {
+                buffer[length] = self.line[self.offset];
+                length += 1;
+                self.offset = (self.offset + 1) % self.line.len();
+                if self.offset == 0 {
+                    self.left -= 1;
+                }
+            }
+            Ok(length)
+        }
+    }
+
+    #[test]
+    fn large_sources_stream() {
+        // Far more comments than the program they hold stream through a fixed
+        // amount of memory, even when all of them are on a single line
+        let lines: [&'static [u8]; 2] = [b"this line is only a comment apart from the command at its end +\n",
+                                         b"this comment never ends its line but it has a command in it + "];
+        for &line in &lines {
+            let comments = Comments { left: (64 << 20) / line.len(), line, offset: 0 };
+            let program = compile_reader(comments, &CompileOptions::default()).expect("the source streams");
+            assert_eq!(program.code.len(), 1);
+        }
+    }
+
+    #[test]
+    fn blocks_wrap_around_the_tape() {
+        // A straight run long enough to become a block, crossing the left end
+        // of the tape and cancelling out some of its own additions
+        let (pointer, cells) = tape("+++>++>>-<<<--<<+++++>+>>>>>+-->>+[-]++<<<<<<<-.>>.");
+        assert_eq!((pointer, cells), (1, vec![1, 2, 0, 255, 255, 0, 2, 0, 0, 0, 0, 0, 0, 0, 5, 0]));
+    }
+
+    #[test]
+    fn unentered_multiply_loops() {
+        // A multiply loop which is never entered must not reach past the start
+        // of the tape, even one with hard ends
+        let mut cells = vec![0; 16];
+        cells[0] = 1;
+        assert_eq!(tape("[-<+>]+."), (0, cells.clone()));
+
+        let line = Config { bounds: Bounds::Error, ..Config::default() };
+        assert_eq!(finish(optimize(compiled("[-<+>]+.")), &line).unwrap(), (0, cells));
+    }
+
+    #[test]
+    fn machines_run_multiply_loops() {
+        // Multiply loops which do run, one of them reaching past the start of
+        // the tape, must leave the same cells on a machine
+        let mut ahead = [0; 16];
+        ahead[1..3].copy_from_slice(&[6, 9]);
+        let mut behind = [0; 16];
+        behind[1] = 6;
+        behind[15] = 9;
+
+        for &(source, pointer, cells) in &[("+++[->++>+++<<]>.>.", 2, ahead), ("+++[->++<<+++>].", 0, behind)] {
+            assert_eq!(tape(source), (pointer, cells.to_vec()));
+
+            let program = optimize(compiled(source));
+            let mut tape = [0u8; 16];
+            let mut machine = Machine::new(&program, &Config::default(), &mut tape, 0, &b""[..], vec![]);
+            machine.run().unwrap();
+            assert_eq!((machine.pointer(), machine.tape()), (pointer, &cells[..]));
+        }
+    }
+
+    #[test]
+    fn neighbouring_clears() {
+        // Clears which become a single clear of the range, once within the
+        // tape and once crossing its right end
+        assert_eq!(disassemble(&optimize(compiled("[-]>[-]>[-]")).code), "       0  clear 3\n");
+
+        let mut cells = vec![0; 16];
+        cells[3] = 1;
+        assert_eq!(tape("+>+>+>+<<<[-]>[-]>[-]>[-]+."), (3, cells));
+
+        let mut cells = vec![0; 16];
+        cells[14..].copy_from_slice(&[1, 1]);
+        assert_eq!(tape("<<+>+>+<[-]>[-]>[-]>[-]<<<+."), (15, cells));
+    }
+
+    #[test]
+    fn moves_around_the_length_of_the_tape() {
+        // The cells a program leaves after adding to the first cell, adding
+        // to the one it turns around at and subtracting from the one it ends
+        // up on
+        let expected = |turn: usize, end: usize| {
+            let mut cells = vec![0u8; 16];
+            cells[0] += 1;
+            cells[turn] += 1;
+            cells[end] = cells[end].wrapping_sub(1);
+            (end, cells)
+        };
+
+        // Moves which wrap to either side of where they started
+        for length in 14..19 {
+            assert_eq!(tape(&format!("+{}+{}-.", ">".repeat(length), "<".repeat(length * 2 + 1))),
+                       expected(length % 16, (16 - (length + 1) % 16) % 16));
+            assert_eq!(tape(&format!("+{}+{}-.", "<".repeat(length), ">".repeat(length * 3))),
+                       expected((16 - length % 16) % 16, length * 2 % 16));
+        }
+    }
+
+    #[test]
+    fn c_wraps_the_pointer() {
+        let mut c = vec![];
+        emit_c(&optimize(compiled("<+.>>+<<")).code, 16, &mut c).unwrap();
+        let c = String::from_utf8(c).unwrap();
+
+        let lines: Vec<&str> = c.lines().map(str::trim)
+            .skip_while(|&line| line != "size_t p = 0;")
+            .skip(1)
+            .take_while(|&line| line != "return 0;")
+            .collect();
+        assert_eq!(lines, [
+            "m[(p + 15) % TAPE] += 1; p = (p + 15) % TAPE;",
+            "putchar(m[p]);",
+            "m[(p + 2) % TAPE] += 1;",
+        ]);
+    }
+
+    #[test]
+    fn unreachable_code_after_known_cells() {
+        let unreachable = |source: &str, blank| analyze(&compiled(source), TAPE_LENGTH, blank).iter()
+            .any(|warning| matches!(warning.kind, WarningKind::Unreachable { .. }));
+
+        // An earlier program may have left anything in the first cell, but a
+        // loop always leaves a zero behind
+        assert!(unreachable("+[]+.", true));
+        assert!(!unreachable("+[]+.", false));
+        assert!(unreachable("[-]+[]+.", false));
+    }
+}